@@ -5,6 +5,19 @@
 //! - `DATABASE_BUCKET_NAME`: name of the S3 bucekt that contains the database.
 //! - `DATABASE_KEY`: key of the database file in the bucket.
 //! - `OPENAI_API_KEY`: API key for OpenAI.
+//!   Not needed if `OPENAI_AUTH_SCHEME` is "none".
+//! - `OPENAI_BASE_URL`: (optional) base URL of the OpenAI API; e.g., that of
+//!   an Azure OpenAI deployment or an OpenAI-compatible server.
+//! - `OPENAI_AUTH_SCHEME`: (optional) "bearer" (default), "api-key", or
+//!   "none".
+//! - `OPENAI_API_VERSION`: (optional) `api-version` query parameter.
+//! - `OPENAI_ORGANIZATION`: (optional) `OpenAI-Organization` header.
+//! - `OPENAI_PROJECT`: (optional) `OpenAI-Project` header.
+//! - `OPENAI_TIMEOUT`: (optional) timeout of a request in seconds.
+//! - `OPENAI_EMBEDDING_MODEL`: (optional) embedding model; must be the model
+//!   of an Azure OpenAI deployment.
+//! - `OPENAI_EMBEDDING_DIMENSIONS`: (optional) number of dimensions of
+//!   embeddings; required unless the model is a known OpenAI model.
 
 use anyhow::Context;
 use lambda_runtime::{Error, LambdaEvent, service_fn};
@@ -18,11 +31,24 @@ use flechasdb::db::AttributeValue;
 use flechasdb::slice::AsSlice;
 use flechasdb_s3::asyncfs::S3FileSystem;
 
-use mumble_embedding::openai::{EmbeddingRequestBody, create_embeddings};
+use mumble_embedding::openai::OpenAiEmbeddingProvider;
+use mumble_embedding::posts::{CW_SCOPE, Granularity};
+use mumble_embedding::providers::{EmbeddingProvider, embed_one};
+use mumble_embedding::search::{TimeFilter, now_timestamp};
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct Query {
     text: String,
+    // results of any granularity are returned if omitted.
+    #[serde(default)]
+    granularity: Option<Granularity>,
+    // only the default search scope is queried unless true.
+    #[serde(default)]
+    include_cw: bool,
+    // "since", "until", and "recencyDecay".
+    #[serde(flatten)]
+    time_filter: TimeFilter,
 }
 
 async fn function_handler(event: LambdaEvent<Query>) -> Result<Value, Error> {
@@ -32,7 +58,16 @@ async fn function_handler(event: LambdaEvent<Query>) -> Result<Value, Error> {
         .context("no DATABASE_BUCKET_NAME set")?;
     let db_key = env::var("DATABASE_KEY")
         .context("no DATABASE_KEY set")?;
-    let results = query(bucket_name, db_key, query_text.text).await?;
+    let provider = OpenAiEmbeddingProvider::from_env()?;
+    let results = query(
+        &provider,
+        bucket_name,
+        db_key,
+        query_text.text,
+        query_text.granularity,
+        query_text.include_cw,
+        query_text.time_filter,
+    ).await?;
     event!(
         Level::INFO,
         "total elapsed {} μs",
@@ -42,26 +77,17 @@ async fn function_handler(event: LambdaEvent<Query>) -> Result<Value, Error> {
 }
 
 async fn query(
+    provider: &dyn EmbeddingProvider,
     bucket_name: String,
     db_key: String,
     query_text: String,
+    granularity: Option<Granularity>,
+    include_cw: bool,
+    time_filter: TimeFilter,
 ) -> Result<Vec<String>, Error> {
     event!(Level::INFO, "creating embedding for the query");
     let time = std::time::Instant::now();
-    let openai_api_key = env::var("OPENAI_API_KEY")
-        .context("no OPENAI_API_KEY set")?;
-    let query_embedding = create_embeddings(
-        &EmbeddingRequestBody {
-            model: "text-embedding-ada-002".to_string(),
-            input: vec![query_text.to_string()],
-            user: Some("mumble_embedding".to_string()),
-        },
-        openai_api_key,
-    ).await?;
-    let query_vector: Vec<f32> = query_embedding.data[0].embedding
-        .iter()
-        .map(|x| *x as f32)
-        .collect();
+    let query_vector = embed_one(provider, &query_text).await?;
     event!(
         Level::INFO,
         "created embedding for the query in {} μs",
@@ -89,23 +115,44 @@ async fn query(
         "loaded database in {} μs",
         time.elapsed().as_micros(),
     );
-    do_query(&db, &query_vector[..]).await
+    do_query(
+        &db,
+        &query_vector[..],
+        provider.model_id(),
+        granularity,
+        include_cw,
+        time_filter,
+    ).await
 }
 
 async fn do_query<V>(
     db: &Database<f32, S3FileSystem>,
     query_vector: V,
+    model_id: &str,
+    granularity: Option<Granularity>,
+    include_cw: bool,
+    time_filter: TimeFilter,
 ) -> Result<Vec<String>, Error>
 where
     V: AsSlice<f32>,
 {
     const K: usize = 10; // k-nearest neighbors
     const NPROBE: usize = 1;
+    // over-samples neighbors to filter by granularity, scope, and time
+    const OVERSAMPLING: usize = 5;
+    let k = if granularity.is_some()
+        || !include_cw
+        || !time_filter.is_empty()
+    {
+        K * OVERSAMPLING
+    } else {
+        K
+    };
     // queries k-NN
     let time = std::time::Instant::now();
     let results = db.query_with_events(
         query_vector.as_slice(),
-        K.try_into().unwrap(),
+        k.try_into().unwrap(),
         NPROBE.try_into().unwrap(),
         |event| {
             event!(
@@ -117,20 +164,91 @@ where
         },
     ).await?;
     event!(Level::INFO, "queried k-NN in {} μs", time.elapsed().as_micros());
+    // every vector has the same model; a database built before the model
+    // was recorded has none
+    if let Some(result) = results.first() {
+        let value = result.get_attribute("model").await
+            .context("failed to get 'model'")?;
+        match value {
+            Some(AttributeValue::String(s)) if s != model_id => {
+                return Err(anyhow::anyhow!(
+                    "query model {} differs from database model {}",
+                    model_id,
+                    s,
+                ).into());
+            },
+            Some(AttributeValue::String(_)) => {},
+            Some(AttributeValue::Uint64(_)) => {
+                return Err(anyhow::anyhow!("model must be a string").into());
+            },
+            None => {
+                event!(Level::WARN, "no embedding model in the database");
+            },
+        }
+    }
 
     let time = std::time::Instant::now();
-    let results: Result<_, Error> = futures::future::try_join_all(
+    let now = now_timestamp();
+    let results: Result<Vec<_>, Error> = futures::future::try_join_all(
         results.into_iter().map(|result| async move {
             let content_id = result.get_attribute("content_id").await
                 .context("failed to get 'content_id'")?;
-            Ok((result, content_id))
+            let matches = if let Some(granularity) = granularity {
+                let value = result.get_attribute("granularity").await
+                    .context("failed to get 'granularity'")?;
+                // treats a missing granularity as a sentence
+                match value {
+                    Some(AttributeValue::String(s)) =>
+                        s == granularity.as_str(),
+                    Some(AttributeValue::Uint64(_)) => false,
+                    None => granularity == Granularity::Sentence,
+                }
+            } else {
+                true
+            };
+            let matches = matches && if include_cw {
+                true
+            } else {
+                let value = result.get_attribute("scope").await
+                    .context("failed to get 'scope'")?;
+                // treats a missing scope as the default
+                match value {
+                    Some(AttributeValue::String(s)) => s != CW_SCOPE,
+                    Some(AttributeValue::Uint64(_)) => false,
+                    None => true,
+                }
+            };
+            let published = if time_filter.is_empty() {
+                None
+            } else {
+                let value = result.get_attribute("published").await
+                    .context("failed to get 'published'")?;
+                match value {
+                    Some(AttributeValue::Uint64(t)) => Some(t),
+                    _ => None,
+                }
+            };
+            let matches = matches && time_filter.matches(published);
+            let score =
+                time_filter.score(result.squared_distance, published, now);
+            Ok((result, content_id, matches, score))
         }),
     ).await;
-    let results = results.map_err(|err| anyhow::anyhow!(
-        "failed to get 'content_id': {}",
-        err,
-    ))?;
-    for (i, (result, content_id)) in results.iter().enumerate() {
+    let mut results: Vec<_> = results
+        .map_err(|err| anyhow::anyhow!(
+            "failed to get 'content_id': {}",
+            err,
+        ))?
+        .into_iter()
+        .filter(|(_, _, matches, _)| *matches)
+        .map(|(result, content_id, _, score)| (result, content_id, score))
+        .collect();
+    // stable, so ties keep the order of distances
+    if time_filter.has_decay() {
+        results.sort_by(|(_, _, lhs), (_, _, rhs)| rhs.total_cmp(lhs));
+    }
+    results.truncate(K);
+    for (i, (result, content_id, _)) in results.iter().enumerate() {
         event!(
             Level::INFO,
             "result[{}]:\ncontent ID: {:?}\napprox. distance: {}",
@@ -144,7 +262,7 @@ where
     Ok(
         results
             .into_iter()
-            .map(|(_, content_id)| {
+            .map(|(_, content_id, _)| {
                 content_id
                     .map(|x| match x {
                         AttributeValue::String(s) => Ok(s.clone()),
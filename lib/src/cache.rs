@@ -0,0 +1,185 @@
+//! Content-addressed cache of embeddings.
+//!
+//! An embedding is saved in a file named after the hash of the model and
+//! normalized text, so that the same text is embedded only once across posts
+//! and runs.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use sha2::{Digest, Sha256};
+use std::fs::{File, create_dir_all, remove_file, rename};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::error::Error;
+
+// Sequence number of temporary files made by this process.
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// Content-addressed cache of embeddings.
+///
+/// Multiple processes or caches may share a directory.
+pub struct EmbeddingCache {
+    dir: PathBuf,
+    hits: Cell<usize>,
+    misses: Cell<usize>,
+}
+
+impl EmbeddingCache {
+    /// Opens the cache in a given directory.
+    ///
+    /// Creates the directory if it does not exist.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        if !dir.exists() {
+            create_dir_all(&dir)?;
+        }
+        Ok(Self {
+            dir,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        })
+    }
+
+    /// Returns the cached embedding of a given text.
+    ///
+    /// Counts a hit or miss. A corrupted entry is a miss, and will be
+    /// overwritten by [`EmbeddingCache::put`].
+    pub fn get(
+        &self,
+        model: &str,
+        text: &str,
+    ) -> Result<Option<Vec<f32>>, Error> {
+        let path = self.path_of(model, text);
+        if !path.exists() {
+            self.record_miss();
+            return Ok(None);
+        }
+        let file = File::open(&path)?;
+        match serde_json::from_reader(file) {
+            Ok(embedding) => {
+                self.record_hit();
+                Ok(Some(embedding))
+            },
+            Err(e) => {
+                println!("WARNING: corrupted cache entry {:?}: {}", path, e);
+                self.record_miss();
+                Ok(None)
+            },
+        }
+    }
+
+    /// Saves the embedding of a given text.
+    ///
+    /// Writes a temporary file and renames it, so that no reader sees a
+    /// partially written entry.
+    pub fn put(
+        &self,
+        model: &str,
+        text: &str,
+        embedding: &[f32],
+    ) -> Result<(), Error> {
+        let path = self.path_of(model, text);
+        let temp_path = path.with_extension(format!(
+            "{}-{}.tmp",
+            process::id(),
+            NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed),
+        ));
+        let written = File::create(&temp_path)
+            .map_err(Error::from)
+            .and_then(|file| Ok(serde_json::to_writer(file, embedding)?))
+            .and_then(|_| Ok(rename(&temp_path, &path)?));
+        if written.is_err() {
+            let _ = remove_file(&temp_path);
+        }
+        written
+    }
+
+    /// Counts a hit not served from the cache; e.g., a duplicate text in the
+    /// same batch.
+    pub fn record_hit(&self) {
+        self.hits.set(self.hits.get() + 1);
+    }
+
+    // Counts a miss.
+    fn record_miss(&self) {
+        self.misses.set(self.misses.get() + 1);
+    }
+
+    /// Returns the number of hits.
+    pub fn hits(&self) -> usize {
+        self.hits.get()
+    }
+
+    /// Returns the number of misses.
+    pub fn misses(&self) -> usize {
+        self.misses.get()
+    }
+
+    /// Returns the ratio of hits to lookups.
+    ///
+    /// Zero if there has been no lookup.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits() + self.misses();
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits() as f64 / lookups as f64
+        }
+    }
+
+    // Returns the path to the file of a given text.
+    fn path_of(&self, model: &str, text: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", cache_key(model, text)))
+    }
+}
+
+/// Returns the cache key of a given text.
+///
+/// A hex-encoded SHA-256 digest of the model and normalized text.
+pub fn cache_key(model: &str, text: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(model.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(normalize_text(text).as_bytes());
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Normalizes a text to be embedded.
+///
+/// Collapses whitespaces.
+pub fn normalize_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedding_cache_reuses_normalized_texts() {
+        let dir = std::env::temp_dir()
+            .join(format!("mumble-embedding-cache-{}", std::process::id()));
+        let cache = EmbeddingCache::open(&dir).unwrap();
+        assert_eq!(cache.get("model", "Good morning!").unwrap(), None);
+        cache.put("model", "Good morning!", &[0.5, -0.5]).unwrap();
+        assert_eq!(
+            cache.get("model", "  Good\nmorning! ").unwrap(),
+            Some(vec![0.5, -0.5]),
+        );
+        assert_eq!(cache.get("other-model", "Good morning!").unwrap(), None);
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 2);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // a partially written entry from an older version
+        std::fs::write(cache.path_of("model", "Good night!"), "[0.5,").unwrap();
+        assert_eq!(cache.get("model", "Good night!").unwrap(), None);
+        cache.put("model", "Good night!", &[0.25]).unwrap();
+        assert_eq!(
+            cache.get("model", "Good night!").unwrap(),
+            Some(vec![0.25]),
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
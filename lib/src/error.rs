@@ -1,27 +1,66 @@
 //! Common error.
 
+use crate::openai::ApiError;
+
 /// Common error.
 #[derive(Debug)]
 pub enum Error {
     InvalidData(String),
     InvalidContext(String),
     HttpError(reqwest::StatusCode),
+    OpenAiError(reqwest::StatusCode, ApiError),
     SerdeJsonError(serde_json::Error),
     ReqwestError(reqwest::Error),
     AwsSdkError(String),
+    IoError(std::io::Error),
+    PostLoadError(String, Box<Error>),
 }
 
 impl std::error::Error for Error {}
 
+impl Error {
+    /// Returns if the error is because a listed post failed to load.
+    ///
+    /// Other errors from a [`crate::sources::PostSource`] mean that the
+    /// listing itself failed.
+    pub fn is_post_load_error(&self) -> bool {
+        matches!(self, Error::PostLoadError(_, _))
+    }
+
+    /// Returns if the error is because an input to the OpenAI API exceeded
+    /// the context length of the model.
+    ///
+    /// Splitting the input and retrying may work.
+    pub fn is_context_length_exceeded(&self) -> bool {
+        matches!(
+            self,
+            Error::OpenAiError(_, e) if e.is_context_length_exceeded(),
+        )
+    }
+
+    /// Returns if the error is because the quota of the OpenAI API has run
+    /// out.
+    ///
+    /// No further request will succeed until the quota is topped up.
+    pub fn is_insufficient_quota(&self) -> bool {
+        matches!(self, Error::OpenAiError(_, e) if e.is_insufficient_quota())
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::InvalidData(s) => write!(f, "Invalid data: {}", s),
             Error::InvalidContext(s) => write!(f, "Invalid context: {}", s),
             Error::HttpError(s) => write!(f, "HTTP error: {}", s),
+            Error::OpenAiError(s, e) =>
+                write!(f, "OpenAI error ({}): {}", s, e),
             Error::SerdeJsonError(e) => write!(f, "serde_json::Error: {}", e),
             Error::ReqwestError(e) => write!(f, "reqwest::Error: {}", e),
             Error::AwsSdkError(s) => write!(f, "AWS SDK error: {}", s),
+            Error::IoError(e) => write!(f, "std::io::Error: {}", e),
+            Error::PostLoadError(key, e) =>
+                write!(f, "Failed to load post {}: {}", key, e),
         }
     }
 }
@@ -38,6 +77,12 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e)
+    }
+}
+
 impl<E, R> From<aws_sdk_s3::error::SdkError<E, R>> for Error {
     fn from(e: aws_sdk_s3::error::SdkError<E, R>) -> Self {
         Error::AwsSdkError(format!("{}", e))
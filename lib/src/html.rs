@@ -0,0 +1,268 @@
+//! Converts HTML into Markdown.
+//!
+//! Deals with the limited HTML of posts on Mastodon and alike; i.e.,
+//! paragraphs, line breaks, links, and a few inline elements.
+
+/// Converts HTML into Markdown.
+///
+/// Block elements become paragraphs separated by a blank line, `<br>`
+/// becomes a line break, and `<pre>` becomes a fenced code block. Other
+/// elements are reduced to their texts, and characters special to Markdown
+/// are escaped. Contents of `<script>` and `<style>` are dropped.
+pub fn html_to_markdown(html: &str) -> String {
+    let mut converter = Converter::default();
+    let mut rest = html;
+    while !rest.is_empty() {
+        if let Some(after_lt) = rest.strip_prefix('<') {
+            match parse_tag(after_lt) {
+                Some((tag, len)) => {
+                    converter.tag(tag);
+                    rest = &after_lt[len..];
+                    continue;
+                },
+                None => {
+                    // treats a stray '<' as text
+                    converter.text("<");
+                    rest = after_lt;
+                    continue;
+                },
+            }
+        }
+        let end = rest.find('<').unwrap_or(rest.len()).max(1);
+        converter.text(&decode_entities(&rest[..end]));
+        rest = &rest[end..];
+    }
+    converter.finish()
+}
+
+// Tag.
+#[derive(Debug, Eq, PartialEq)]
+struct Tag {
+    name: String,
+    closing: bool,
+}
+
+// Parses a tag after '<'.
+//
+// Returns the tag and the length up to and including '>'.
+// Returns `None` if the text does not start with a tag.
+// A comment or declaration is returned as a tag with an empty name.
+fn parse_tag(text: &str) -> Option<(Tag, usize)> {
+    if let Some(comment) = text.strip_prefix("!--") {
+        let end = comment.find("-->")? + 3 + 3;
+        return Some((Tag { name: String::new(), closing: false }, end));
+    }
+    let (closing, body) = match text.strip_prefix('/') {
+        Some(body) => (true, body),
+        None => (false, text),
+    };
+    let first = body.chars().next()?;
+    if !first.is_ascii_alphabetic() && first != '!' {
+        return None;
+    }
+    // finds '>' outside quoted attribute values
+    let mut quote: Option<char> = None;
+    let mut end = None;
+    for (i, ch) in body.char_indices() {
+        match quote {
+            Some(q) if ch == q => quote = None,
+            Some(_) => {},
+            None if ch == '"' || ch == '\'' => quote = Some(ch),
+            None if ch == '>' => {
+                end = Some(i);
+                break;
+            },
+            None => {},
+        }
+    }
+    let end = end?;
+    let name: String = body[..end]
+        .chars()
+        .take_while(|ch| ch.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let len = end + 1 + if closing { 1 } else { 0 };
+    Some((Tag { name, closing }, len))
+}
+
+// Decodes character references.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest.find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+        match reference {
+            Some((ch, end)) => {
+                decoded.push(ch);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// Decodes a character reference without '&' and ';'.
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(code) = name.strip_prefix('#') {
+        let code = match code.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => code.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => None,
+    }
+}
+
+// Returns if a given element is a block.
+fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "p" | "div" | "blockquote" | "pre" | "ul" | "ol" | "li"
+            | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "hr" | "table" | "tr",
+    )
+}
+
+// State of a conversion.
+#[derive(Default)]
+struct Converter {
+    output: String,
+    // pending line breaks before the next text
+    pending_breaks: usize,
+    // whether the last output is a whitespace
+    last_space: bool,
+    // depth of <pre>
+    pre_depth: usize,
+    // depth of <script> or <style>
+    skip_depth: usize,
+}
+
+impl Converter {
+    fn tag(&mut self, tag: Tag) {
+        let name = tag.name.as_str();
+        if name == "script" || name == "style" {
+            if tag.closing {
+                self.skip_depth = self.skip_depth.saturating_sub(1);
+            } else {
+                self.skip_depth += 1;
+            }
+            return;
+        }
+        if name == "pre" {
+            // fences preformatted text
+            if tag.closing {
+                if self.pre_depth == 1 {
+                    self.output.push_str("\n```");
+                }
+                self.pre_depth = self.pre_depth.saturating_sub(1);
+            } else {
+                if self.pre_depth == 0 {
+                    self.pending_breaks = 2;
+                    self.flush_breaks();
+                    self.output.push_str("```\n");
+                }
+                self.pre_depth += 1;
+                return;
+            }
+        }
+        if name == "br" {
+            self.pending_breaks = self.pending_breaks.max(1);
+        } else if is_block(name) {
+            self.pending_breaks = 2;
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.skip_depth > 0 {
+            return;
+        }
+        for ch in text.chars() {
+            if self.pre_depth == 0 && ch.is_whitespace() {
+                if !self.last_space && self.pending_breaks == 0 {
+                    self.push_space();
+                }
+                continue;
+            }
+            self.flush_breaks();
+            if self.pre_depth == 0 && is_markdown_special(ch) {
+                self.output.push('\\');
+            }
+            self.output.push(ch);
+            self.last_space = ch.is_whitespace();
+        }
+    }
+
+    fn push_space(&mut self) {
+        if !self.output.is_empty() {
+            self.output.push(' ');
+            self.last_space = true;
+        }
+    }
+
+    fn flush_breaks(&mut self) {
+        if self.pending_breaks > 0 && !self.output.is_empty() {
+            // trailing spaces would be a hard line break in Markdown
+            let trimmed = self.output.trim_end_matches(' ').len();
+            self.output.truncate(trimmed);
+            for _ in 0..self.pending_breaks {
+                self.output.push('\n');
+            }
+            self.last_space = true;
+        }
+        self.pending_breaks = 0;
+    }
+
+    fn finish(self) -> String {
+        self.output.trim_end().to_string()
+    }
+}
+
+// Returns if a given character is special to Markdown.
+fn is_markdown_special(ch: char) -> bool {
+    matches!(ch, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '|')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_to_markdown_converts_mastodon_content() {
+        let html = concat!(
+            "<p>Hello, <span class=\"h-card\"><a href=\"https://example.com/",
+            "@alice\" class=\"u-url mention\">@<span>alice</span></a></span>",
+            "!<br />Fish &amp; chips*</p><p><a href=\"https://example.com/",
+            "tags/rust\" class=\"mention hashtag\" rel=\"tag\">#<span>rust",
+            "</span></a></p>",
+        );
+        assert_eq!(
+            html_to_markdown(html),
+            "Hello, @alice!\nFish & chips\\*\n\n\\#rust",
+        );
+    }
+
+    #[test]
+    fn html_to_markdown_keeps_preformatted_text() {
+        let html = "<pre><code>let x = 1;\n  x * 2</code></pre><p>a  b</p>";
+        assert_eq!(
+            html_to_markdown(html),
+            "```\nlet x = 1;\n  x * 2\n```\n\na b",
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Imports posts exported from other platforms.
+//!
+//! Supports a Mastodon account export and a Misskey notes export.
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use futures::stream::LocalBoxStream;
+use serde::Deserialize;
+use std::fs::File;
+use std::path::{Component, PathBuf};
+
+use crate::error::Error;
+use crate::html::html_to_markdown;
+use crate::posts::{
+    PUBLIC_AUDIENCE,
+    Post,
+    PostAttachment,
+    PostOrigin,
+    PostSourceContent,
+};
+use crate::sources::{
+    PostSource,
+    load_outbox_items,
+    parse_outbox_items,
+    post_from_outbox_item,
+    stream_loaded_posts,
+};
+
+/// Name of Mastodon as a source platform.
+pub const MASTODON: &str = "mastodon";
+
+/// Name of Misskey as a source platform.
+pub const MISSKEY: &str = "misskey";
+
+// Media type of the converted source of an imported post.
+const MARKDOWN_MEDIA_TYPE: &str = "text/markdown";
+
+/// Mastodon account export.
+///
+/// Reads `outbox.json` in the archive (`.tar.gz`), in the directory where
+/// the archive is extracted, or the `outbox.json` file itself.
+pub struct MastodonArchiveSource {
+    path: PathBuf,
+}
+
+impl MastodonArchiveSource {
+    /// Creates a source of the posts in a given export.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Loads the items in the outbox.
+    fn load_items(&self) -> Result<Vec<serde_json::Value>, Error> {
+        println!("loading Mastodon export: {:?}", self.path);
+        if self.path.is_dir() {
+            return load_outbox_items(self.path.join("outbox.json"));
+        }
+        if self.path.extension() == Some("json".as_ref()) {
+            return load_outbox_items(&self.path);
+        }
+        let file = File::open(&self.path)?;
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+        for entry in archive.entries()? {
+            let entry = entry?;
+            // only the outbox at the root
+            let is_outbox = {
+                let path = entry.path()?;
+                let mut components = path
+                    .components()
+                    .filter(|c| !matches!(c, Component::CurDir));
+                matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(name)), None)
+                        if name == "outbox.json",
+                )
+            };
+            if is_outbox {
+                return parse_outbox_items(entry);
+            }
+        }
+        Err(Error::InvalidData("no outbox.json in the archive".to_string()))
+    }
+}
+
+impl PostSource for MastodonArchiveSource {
+    fn list_posts_except<'a>(
+        &self,
+        skip: Box<dyn Fn(&PostOrigin) -> bool + 'a>,
+    ) -> LocalBoxStream<'a, Result<Post, Error>> {
+        let posts = self.load_items().map(|items| items
+            .into_iter()
+            .filter_map(post_from_outbox_item)
+            .map(|post| post.map(convert_mastodon_post))
+            .collect());
+        stream_loaded_posts(posts, skip)
+    }
+}
+
+/// Converts a post from Mastodon.
+///
+/// The HTML content is converted into Markdown as the source.
+pub fn convert_mastodon_post(mut post: Post) -> Post {
+    convert_html_content(&mut post);
+    post.source_platform = Some(MASTODON.to_string());
+    post
+}
+
+/// Converts the HTML content of a post into Markdown as the source.
+///
+/// Does nothing if the post has a source; e.g., a post on Mumble.
+pub fn convert_html_content(post: &mut Post) {
+    if post.source.is_none() && !post.content.is_empty() {
+        post.source = Some(PostSourceContent {
+            content: html_to_markdown(&post.content),
+            media_type: MARKDOWN_MEDIA_TYPE.to_string(),
+        });
+    }
+}
+
+/// Misskey notes export.
+///
+/// The export is a JSON array of notes. Misskey does not export the URLs of
+/// notes, so the ID of a post is made from the base URL of the server;
+/// i.e., `{base_url}/notes/{note_id}`.
+pub struct MisskeyNotesSource {
+    path: PathBuf,
+    base_url: String,
+}
+
+impl MisskeyNotesSource {
+    /// Creates a source of the notes in a given export.
+    ///
+    /// `base_url` is the URL of the Misskey server; e.g.,
+    /// "https://misskey.io".
+    pub fn new(path: impl Into<PathBuf>, base_url: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            base_url: base_url.into(),
+        }
+    }
+
+    // Loads the notes.
+    fn load_notes(&self) -> Result<Vec<MisskeyNote>, Error> {
+        println!("loading Misskey notes export: {:?}", self.path);
+        let file = File::open(&self.path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+impl PostSource for MisskeyNotesSource {
+    fn list_posts_except<'a>(
+        &self,
+        skip: Box<dyn Fn(&PostOrigin) -> bool + 'a>,
+    ) -> LocalBoxStream<'a, Result<Post, Error>> {
+        let posts = self.load_notes().map(|notes| notes
+            .into_iter()
+            .filter_map(|note| convert_misskey_note(note, &self.base_url))
+            .map(Ok)
+            .collect());
+        stream_loaded_posts(posts, skip)
+    }
+}
+
+/// Note in a Misskey notes export.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MisskeyNote {
+    /// ID.
+    pub id: String,
+    /// Text in MFM (Misskey Flavored Markdown).
+    ///
+    /// `None` for a renote without a quote.
+    pub text: Option<String>,
+    /// Created at.
+    pub created_at: DateTime<Utc>,
+    /// Content warning.
+    pub cw: Option<String>,
+    /// ID of the note that this note replies to.
+    pub reply_id: Option<String>,
+    /// Visibility; e.g., "public" and "home".
+    pub visibility: Option<String>,
+    /// Attached files.
+    #[serde(default)]
+    pub files: Vec<MisskeyFile>,
+}
+
+/// File attached to a Misskey note.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MisskeyFile {
+    /// MIME type.
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    /// URL.
+    pub url: Option<String>,
+    /// Comment; i.e., alt text.
+    pub comment: Option<String>,
+}
+
+/// Converts a Misskey note into a post.
+///
+/// The MFM text is converted into Markdown as the source, and the visibility
+/// into the audience.
+/// Returns `None` if the note has no text; e.g., a renote without a quote.
+pub fn convert_misskey_note(note: MisskeyNote, base_url: &str) -> Option<Post> {
+    let text = note.text?;
+    let base_url = base_url.trim_end_matches('/');
+    let note_url = |id: &str| format!("{}/notes/{}", base_url, id);
+    let id = note_url(&note.id);
+    // "home" is unlisted; i.e., public but not on the public timelines
+    let (to, cc) = match note.visibility.as_deref() {
+        Some("public") => (vec![PUBLIC_AUDIENCE.to_string()], Vec::new()),
+        Some("home") => (Vec::new(), vec![PUBLIC_AUDIENCE.to_string()]),
+        _ => (Vec::new(), Vec::new()),
+    };
+    Some(Post {
+        origin: Some(PostOrigin {
+            key: id.clone(),
+            e_tag: None,
+        }),
+        id,
+        type_: "Note".to_string(),
+        source: Some(PostSourceContent {
+            content: mfm_to_markdown(&text),
+            media_type: MARKDOWN_MEDIA_TYPE.to_string(),
+        }),
+        content: text,
+        published: Some(note.created_at),
+        summary: note.cw,
+        in_reply_to: note.reply_id.as_deref().map(note_url),
+        to,
+        cc,
+        attachment: note.files
+            .into_iter()
+            .map(|file| PostAttachment {
+                type_: "Document".to_string(),
+                media_type: file.type_,
+                url: file.url,
+                name: file.comment,
+            })
+            .collect(),
+        source_platform: Some(MISSKEY.to_string()),
+        ..Post::default()
+    })
+}
+
+/// Converts MFM (Misskey Flavored Markdown) into Markdown.
+///
+/// Unwraps functions; e.g., `$[x2 text]` becomes `text`, and drops
+/// decoration tags; e.g., `<small>` and `<center>`. Other syntax is common to
+/// Markdown or left intact.
+pub fn mfm_to_markdown(text: &str) -> String {
+    const TAGS: [&str; 7] = ["small", "center", "plain", "i", "b", "s", "u"];
+    let mut converted = unwrap_mfm_functions(text);
+    for tag in TAGS {
+        converted = converted
+            .replace(&format!("<{}>", tag), "")
+            .replace(&format!("</{}>", tag), "");
+    }
+    converted
+}
+
+// Unwraps MFM functions.
+fn unwrap_mfm_functions(text: &str) -> String {
+    let mut unwrapped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("$[") {
+        unwrapped.push_str(&rest[..start]);
+        let function = &rest[start + 2..];
+        match find_mfm_function_body(function) {
+            Some((body, len)) => {
+                unwrapped.push_str(&unwrap_mfm_functions(body));
+                rest = &function[len..];
+            },
+            None => {
+                unwrapped.push_str("$[");
+                rest = function;
+            },
+        }
+    }
+    unwrapped.push_str(rest);
+    unwrapped
+}
+
+// Finds the body of an MFM function after "$[".
+//
+// Returns the body and the length up to and including the closing ']'.
+fn find_mfm_function_body(function: &str) -> Option<(&str, usize)> {
+    let name_end = function.find(|ch: char| ch.is_whitespace())?;
+    if name_end == 0 || function[..name_end].contains(['[', ']']) {
+        return None;
+    }
+    let body_start = name_end + 1;
+    let mut depth = 0;
+    for (i, ch) in function[body_start..].char_indices() {
+        match ch {
+            '[' => depth += 1,
+            ']' if depth == 0 => {
+                let end = body_start + i;
+                return Some((&function[body_start..end], end + 1));
+            },
+            ']' => depth -= 1,
+            _ => {},
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_misskey_note_maps_fields() {
+        let note: MisskeyNote = serde_json::from_str(r#"{
+            "id": "9abc",
+            "text": "$[x2 Hello] <small>world</small>!",
+            "createdAt": "2023-09-20T00:00:00.000Z",
+            "cw": null,
+            "replyId": "9abb",
+            "visibility": "public",
+            "files": [{ "type": "image/png", "comment": "A cat" }]
+        }"#).unwrap();
+        let post = convert_misskey_note(note, "https://misskey.example/")
+            .unwrap();
+        assert_eq!(post.id, "https://misskey.example/notes/9abc");
+        assert_eq!(post.text(), "Hello world!");
+        assert_eq!(
+            post.in_reply_to.as_deref(),
+            Some("https://misskey.example/notes/9abb"),
+        );
+        assert_eq!(post.attachment[0].name.as_deref(), Some("A cat"));
+        assert_eq!(post.source_platform.as_deref(), Some(MISSKEY));
+        assert!(post.is_public());
+    }
+
+    #[test]
+    fn mfm_to_markdown_unwraps_nested_functions() {
+        assert_eq!(
+            mfm_to_markdown("$[spin.speed=2s $[x2 [link](https://a)]] $[ x]"),
+            "[link](https://a) $[ x]",
+        );
+    }
+}
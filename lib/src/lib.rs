@@ -1,7 +1,21 @@
+pub mod cache;
 pub mod error;
+pub mod html;
+pub mod import;
+pub mod local;
+pub mod manifest;
 pub mod markdown;
+#[cfg(feature = "onnx")]
+pub mod onnx;
 pub mod openai;
 pub mod posts;
+pub mod providers;
+pub mod remote;
 pub mod s3;
+pub mod search;
+pub mod sources;
 pub mod streams;
+#[cfg(test)]
+mod stub_server;
 pub mod text;
+pub mod threads;
@@ -0,0 +1,262 @@
+//! Local embeddings made without any network access.
+//!
+//! Embeddings are made by feature hashing of word and character n-grams, so
+//! they are deterministic and need neither credentials nor model files.
+//! They capture lexical rather than semantic similarity, and are meant for
+//! tests and air-gapped runs.
+
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+
+use crate::cache::normalize_text;
+use crate::error::Error;
+use crate::providers::EmbeddingProvider;
+
+/// Default number of dimensions of local embeddings.
+pub const DEFAULT_LOCAL_DIMENSIONS: usize = 512;
+
+// Longest word n-grams.
+const MAX_WORD_NGRAM: usize = 2;
+
+// Length of character n-grams.
+const CHAR_NGRAM: usize = 3;
+
+/// Provider of local embeddings.
+///
+/// A text is lowercased and its whitespaces are collapsed. Then word
+/// unigrams and bigrams, and character trigrams are hashed into buckets with
+/// signs, weighted by their counts, and optionally by [`IdfWeights`].
+/// Vectors are normalized to unit length.
+#[derive(Clone, Debug)]
+pub struct LocalEmbeddingProvider {
+    dimensions: usize,
+    idf: Option<IdfWeights>,
+    model_id: String,
+}
+
+impl LocalEmbeddingProvider {
+    /// Creates a provider of embeddings of given dimensions.
+    ///
+    /// Panics if `dimensions` is zero.
+    pub fn new(dimensions: usize) -> Self {
+        assert!(dimensions > 0, "dimensions must be positive");
+        Self {
+            dimensions,
+            idf: None,
+            model_id: format!("local-hashing-{}", dimensions),
+        }
+    }
+
+    /// Weights features by given IDF weights.
+    ///
+    /// Fails if the weights are of different dimensions. The model ID
+    /// includes the fingerprint of the weights, because embeddings with
+    /// different weights are not comparable.
+    pub fn with_idf(mut self, idf: IdfWeights) -> Result<Self, Error> {
+        if idf.dimensions() != self.dimensions {
+            return Err(Error::InvalidData(format!(
+                "IDF weights have {} dimensions but {} expected",
+                idf.dimensions(),
+                self.dimensions,
+            )));
+        }
+        self.model_id = format!(
+            "local-hashing-{}-idf-{}",
+            self.dimensions,
+            idf.fingerprint(),
+        );
+        self.idf = Some(idf);
+        Ok(self)
+    }
+
+    /// Embeds a given text.
+    pub fn embed_text(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0f32; self.dimensions];
+        for (hash, count) in count_features(text) {
+            let bucket = bucket_of(hash, self.dimensions);
+            let weight = match self.idf.as_ref() {
+                Some(idf) => count as f32 * idf.weight(bucket),
+                None => count as f32,
+            };
+            vector[bucket] += sign_of(hash) * weight;
+        }
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+impl EmbeddingProvider for LocalEmbeddingProvider {
+    fn embed<'a>(
+        &'a self,
+        inputs: &'a [String],
+    ) -> LocalBoxFuture<'a, Result<Vec<Vec<f32>>, Error>> {
+        let vectors = inputs
+            .iter()
+            .map(|input| self.embed_text(input))
+            .collect();
+        Box::pin(async move { Ok(vectors) })
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+}
+
+/// Inverse document frequencies of hashed features.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdfWeights {
+    /// Number of documents.
+    pub num_documents: u64,
+    /// Number of documents that have a feature in each bucket.
+    pub document_frequencies: Vec<u64>,
+}
+
+impl IdfWeights {
+    /// Counts the document frequencies of given texts.
+    ///
+    /// Panics if `dimensions` is zero.
+    pub fn fit<'a>(
+        texts: impl IntoIterator<Item = &'a str>,
+        dimensions: usize,
+    ) -> Self {
+        assert!(dimensions > 0, "dimensions must be positive");
+        let mut num_documents = 0;
+        let mut document_frequencies = vec![0; dimensions];
+        for text in texts {
+            num_documents += 1;
+            let buckets: HashSet<usize> = count_features(text)
+                .into_keys()
+                .map(|hash| bucket_of(hash, dimensions))
+                .collect();
+            for bucket in buckets {
+                document_frequencies[bucket] += 1;
+            }
+        }
+        Self { num_documents, document_frequencies }
+    }
+
+    /// Loads weights from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let weights: Self = serde_json::from_reader(File::open(path)?)?;
+        if weights.document_frequencies.is_empty() {
+            return Err(Error::InvalidData("empty IDF weights".to_string()));
+        }
+        Ok(weights)
+    }
+
+    /// Saves the weights in a JSON file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        serde_json::to_writer(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    /// Returns the number of dimensions.
+    pub fn dimensions(&self) -> usize {
+        self.document_frequencies.len()
+    }
+
+    // Returns the smoothed IDF of a bucket.
+    fn weight(&self, bucket: usize) -> f32 {
+        let n = self.num_documents as f32;
+        let df = self.document_frequencies[bucket] as f32;
+        ((1.0 + n) / (1.0 + df)).ln() + 1.0
+    }
+
+    // Returns the first 8 hex digits of the SHA-256 digest of the weights.
+    fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.num_documents.to_le_bytes());
+        for df in self.document_frequencies.iter() {
+            hasher.update(df.to_le_bytes());
+        }
+        hasher.finalize()[..4].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// Counts the hashed features of a text.
+fn count_features(text: &str) -> HashMap<u64, usize> {
+    let text = normalize_text(text).to_lowercase();
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    let mut add = |kind: &str, feature: &str| {
+        *counts.entry(hash_feature(kind, feature)).or_default() += 1;
+    };
+    let words: Vec<&str> = text
+        .split(|ch: char| !ch.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    for n in 1..=MAX_WORD_NGRAM {
+        for ngram in words.windows(n) {
+            add("w", &ngram.join(" "));
+        }
+    }
+    // pads the text so that the first and last characters make trigrams
+    let chars: Vec<char> = format!(" {} ", text).chars().collect();
+    for ngram in chars.windows(CHAR_NGRAM) {
+        add("c", &ngram.iter().collect::<String>());
+    }
+    counts
+}
+
+// Hashes a feature with 64-bit FNV-1a, which is stable across platforms and
+// releases unlike the hasher of the standard library.
+fn hash_feature(kind: &str, feature: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let bytes = kind.bytes().chain([0u8]).chain(feature.bytes());
+    bytes.fold(OFFSET_BASIS, |hash, b| (hash ^ b as u64).wrapping_mul(PRIME))
+}
+
+fn bucket_of(hash: u64, dimensions: usize) -> usize {
+    (hash % dimensions as u64) as usize
+}
+
+// Takes the sign from a bit not used by the bucket of a small dimension, so
+// that collisions cancel out on average.
+fn sign_of(hash: u64) -> f32 {
+    if hash >> 63 == 0 { 1.0 } else { -1.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+    }
+
+    #[test]
+    fn local_embedding_provider_is_deterministic_and_lexical() {
+        let provider = LocalEmbeddingProvider::new(64);
+        let cache = provider.embed_text("The cache broke again.");
+        assert_eq!(cache.len(), 64);
+        assert_eq!(cache, provider.embed_text("the  CACHE broke again."));
+        let norm: f32 = cache.iter().map(|x| x * x).sum();
+        assert!((norm - 1.0).abs() < 1e-5);
+        let similar = provider.embed_text("The cache broke.");
+        let other = provider.embed_text("Curry for lunch today!");
+        assert!(distance(&cache, &similar) < distance(&cache, &other));
+        assert_eq!(provider.model_id(), "local-hashing-64");
+
+        let idf = IdfWeights::fit(
+            ["The cache broke.", "The curry was good."],
+            64,
+        );
+        assert_eq!(idf.num_documents, 2);
+        let weighted = provider.clone().with_idf(idf.clone()).unwrap();
+        assert!(weighted.model_id().starts_with("local-hashing-64-idf-"));
+        assert_ne!(weighted.embed_text("The cache broke."), similar);
+        assert!(LocalEmbeddingProvider::new(32).with_idf(idf).is_err());
+    }
+}
@@ -1,9 +1,23 @@
 use anyhow::{Context, Error, anyhow, bail};
-use clap::{Parser, Subcommand};
-use futures::stream::StreamExt;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use core::ops::Range;
+use core::str::FromStr;
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
-use std::fs::{File, create_dir_all, read_dir};
+use std::fs::{
+    DirEntry,
+    File,
+    create_dir_all,
+    read_dir,
+    read_to_string,
+    remove_file,
+};
 use std::path::Path;
+use std::time::Duration;
 use url::Url;
 
 use flechasdb::db::AttributeValue;
@@ -15,14 +29,69 @@ use flechasdb::slice::AsSlice;
 use flechasdb::vector::BlockVectorSet;
 use flechasdb_s3::syncfs::S3FileSystem;
 
-use mumble_embedding::openai::{EmbeddingRequestBody, create_embeddings};
+use mumble_embedding::cache::EmbeddingCache;
+use mumble_embedding::error::Error as EmbeddingError;
+use mumble_embedding::import::{MastodonArchiveSource, MisskeyNotesSource};
+use mumble_embedding::local::{
+    DEFAULT_LOCAL_DIMENSIONS,
+    IdfWeights,
+    LocalEmbeddingProvider,
+};
+use mumble_embedding::manifest::{
+    MANIFEST_FILE_NAME,
+    Manifest,
+    ManifestEntry,
+    PostChange,
+};
+use mumble_embedding::markdown::{TextBlock, extract_text_blocks};
+#[cfg(feature = "onnx")]
+use mumble_embedding::onnx::OnnxEmbeddingProvider;
+use mumble_embedding::openai::{
+    ClientConfig,
+    OpenAiEmbeddingProvider,
+    RetryPolicy,
+};
 use mumble_embedding::posts::{
+    CW_SCOPE,
+    ContextWindow,
+    CwPolicy,
     Embedding,
+    Granularity,
+    IdScheme,
+    Post,
+    PostFilter,
+    PostSentence,
+    ReplyFilter,
+    SentenceFilter,
+    attach_context,
     create_embeddings_for_sentences,
-    list_posts,
-    split_post_into_sentences,
+    make_content_id,
+    parse_range_id,
+    read_embedding,
+    split_post,
 };
+use mumble_embedding::providers::{
+    EmbeddingProvider,
+    embed_one,
+    estimate_tokens,
+};
+use mumble_embedding::remote::RemoteOutboxSource;
+use mumble_embedding::sources::{
+    DirectoryPostSource,
+    OutboxPostSource,
+    PostSource,
+    S3PostSource,
+    list_s3_usernames,
+};
+use mumble_embedding::search::{TimeFilter, now_timestamp, to_timestamp};
 use mumble_embedding::streams::StreamAsyncExt;
+use mumble_embedding::threads::{ThreadEntry, ThreadIndex, attach_thread};
+use mumble_embedding::text::{
+    LineBreakMode,
+    SegmentationTrace,
+    TransitionInput,
+    trace_sentences,
+};
 
 #[derive(Parser)]
 struct Cli {
@@ -33,11 +102,163 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Creates embeddings for user's posts.
+    #[command(allow_missing_positional = true)]
     Create {
         /// Username whose posts are to be processed.
-        username: String,
+        ///
+        /// Required if the source is "s3" unless `--all-users` is given, and
+        /// ignored otherwise.
+        username: Option<String>,
         /// Output directory where embedding results are to be saved.
         out_dir: String,
+        /// Source of posts.
+        ///
+        /// One of "s3", "dir", "outbox", "mastodon", "misskey", or "remote".
+        /// "s3" loads posts from the S3 bucket specified by
+        /// OBJECTS_BUCKET_NAME environment variable.
+        /// "dir" loads post JSON files in the directory at `--source-path`.
+        /// "outbox" loads an ActivityPub outbox JSON file at `--source-path`.
+        /// "mastodon" imports a Mastodon account export (.tar.gz) at
+        /// `--source-path`.
+        /// "misskey" imports a Misskey notes export (JSON) at `--source-path`.
+        /// "remote" fetches the outbox of the ActivityPub account at
+        /// `--source-path`; e.g., "@alice@mastodon.social".
+        #[arg(long, default_value = "s3")]
+        source: SourceKind,
+        /// Whether to process all the users in the S3 bucket.
+        ///
+        /// Only for the "s3" source. Embeddings of each user are saved in a
+        /// subdirectory `{out_dir}/{username}`, and the summaries of all the
+        /// users in `{out_dir}/summary.json`.
+        #[arg(long)]
+        all_users: bool,
+        /// Maximum number of users processed at the same time with
+        /// `--all-users`.
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+        /// Path to the source of posts.
+        ///
+        /// Required unless the source is "s3".
+        #[arg(long)]
+        source_path: Option<String>,
+        /// Base URL of the Misskey server; e.g., "https://misskey.io".
+        ///
+        /// Required if the source is "misskey" to make the IDs of notes.
+        #[arg(long)]
+        source_base_url: Option<String>,
+        /// Minimum interval in milliseconds between requests to a remote
+        /// server.
+        #[arg(long, default_value_t = 1000)]
+        remote_request_interval: u64,
+        /// Path to the cursor file to resume fetching a remote outbox.
+        ///
+        /// Fetching resumes from the page saved in the file if it exists.
+        /// The file is removed when all the pages have been fetched.
+        #[arg(long)]
+        remote_cursor: Option<String>,
+        /// Processes only posts published at or after this date in RFC 3339;
+        /// e.g., "2023-09-01T00:00:00Z".
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+        /// Processes only posts published before this date in RFC 3339.
+        #[arg(long)]
+        until: Option<DateTime<Utc>>,
+        /// Whether to process posts not addressed to the public; e.g.,
+        /// followers-only posts.
+        ///
+        /// Only public posts are processed otherwise, so that non-public
+        /// posts never end up in the database.
+        #[arg(long)]
+        include_non_public: bool,
+        /// How replies are treated.
+        ///
+        /// One of "include", "exclude", or "only". "exclude" processes only
+        /// top-level posts, and "only" processes only replies.
+        #[arg(long, default_value = "include")]
+        replies: ReplyFilter,
+        /// Whether to drop trivial sentences; e.g., emoji-only, single-word,
+        /// and punctuation-only ones.
+        ///
+        /// Sets the minimum number of characters to 4, tokens to 2, and
+        /// ratio of letters to 0.5, each of which can be overridden.
+        /// Every sentence is embedded otherwise.
+        #[arg(long)]
+        filter_sentences: bool,
+        /// Minimum number of non-whitespace characters in a sentence.
+        ///
+        /// Characters of URLs are not counted.
+        #[arg(long)]
+        min_chars: Option<usize>,
+        /// Minimum number of tokens in a sentence.
+        ///
+        /// Every ideographic or kana character and URL is counted as a
+        /// token.
+        #[arg(long)]
+        min_tokens: Option<usize>,
+        /// Minimum ratio of alphabetic or ideographic characters to
+        /// non-whitespace characters in a sentence.
+        #[arg(long)]
+        min_letter_ratio: Option<f64>,
+        /// Path to a stop-list file.
+        ///
+        /// Each line is a sentence to be dropped.
+        #[arg(long)]
+        stop_list: Option<String>,
+        /// How a line break in a paragraph is treated.
+        ///
+        /// One of "whitespace", "boundary", or "auto". "auto" treats line
+        /// breaks as sentence boundaries if a paragraph has multiple lines
+        /// and none of them ends with a terminal punctuation.
+        #[arg(long, default_value = "whitespace")]
+        line_breaks: LineBreakMode,
+        /// Number of preceding sentences included in the embedding input of
+        /// each sentence.
+        #[arg(long, default_value_t = 0)]
+        context_before: usize,
+        /// Number of following sentences included in the embedding input of
+        /// each sentence.
+        #[arg(long, default_value_t = 0)]
+        context_after: usize,
+        /// Whether to include the nearest preceding heading in the embedding
+        /// input of each sentence.
+        #[arg(long)]
+        context_title: bool,
+        /// Whether to include the summary or first sentence of the parent
+        /// post in the embedding input of each unit of a reply.
+        ///
+        /// The parent must be a post from the same source.
+        #[arg(long)]
+        context_thread: bool,
+        /// Granularities of embeddings to be created.
+        ///
+        /// Comma-separated list of "post", "block", and "sentence".
+        #[arg(long, value_delimiter = ',', default_value = "sentence")]
+        granularity: Vec<Granularity>,
+        /// Directory where embeddings are cached by content.
+        ///
+        /// Share it among output directories to reuse embeddings of the same
+        /// texts. "{out_dir}/.cache" if omitted.
+        #[arg(long)]
+        cache_dir: Option<String>,
+        /// Whether to disable the embedding cache.
+        #[arg(long)]
+        no_cache: bool,
+        /// Scheme of IDs of sentences and text blocks.
+        ///
+        /// "range" identifies a sentence with its range in the post, and
+        /// "content" with the hash of its content and occurrence index.
+        /// Content-hash based IDs survive edits of other sentences.
+        #[arg(long, default_value = "range")]
+        id_scheme: IdScheme,
+        /// Whether to process all the posts.
+        ///
+        /// Only new or edited posts since the last run are processed
+        /// otherwise. Specify this flag after changing other options.
+        #[arg(long)]
+        full: bool,
+        /// Provider of embeddings.
+        #[command(flatten)]
+        provider: ProviderArgs,
     },
     /// Builds a vector database from embedding results.
     Build {
@@ -55,6 +276,23 @@ enum Commands {
         /// flag.
         #[arg(long)]
         s3: bool,
+        /// Whether to leave out orphaned embeddings instead of refusing to
+        /// build.
+        ///
+        /// An embedding is orphaned if the manifest in the input directory
+        /// does not record it; e.g., an embedding of a deleted post.
+        #[arg(long)]
+        skip_orphans: bool,
+        /// Policy on contents hidden behind content warnings.
+        ///
+        /// One of "include" or "exclude". "exclude" leaves the content and
+        /// attachments of a post with a content warning out of the default
+        /// search scope, whereas the content warning itself stays in.
+        #[arg(long, default_value = "include")]
+        cw_policy: CwPolicy,
+        /// Provider of embeddings for the test query.
+        #[command(flatten)]
+        provider: ProviderArgs,
     },
     /// Queries a vector database.
     Query {
@@ -72,6 +310,77 @@ enum Commands {
         /// Resolves the ID to the contents if this is given.
         #[arg(long)]
         embedding_dir: Option<String>,
+        /// Granularity of results.
+        ///
+        /// One of "post", "block", or "sentence". Results of any granularity
+        /// are returned if omitted.
+        #[arg(long)]
+        granularity: Option<Granularity>,
+        /// Whether to include contents hidden behind content warnings.
+        ///
+        /// Only the default search scope is queried otherwise.
+        #[arg(long)]
+        include_cw: bool,
+        /// Earliest published date of results in RFC 3339 format.
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+        /// Published date that results must be earlier than in RFC 3339
+        /// format.
+        #[arg(long)]
+        until: Option<DateTime<Utc>>,
+        /// Rate of the exponential decay of scores per day of age.
+        ///
+        /// Results are ranked by the similarity alone if omitted. Results
+        /// without a published date decay as the oldest ones.
+        #[arg(long)]
+        recency_decay: Option<f64>,
+        /// Provider of embeddings.
+        #[command(flatten)]
+        provider: ProviderArgs,
+    },
+    /// Migrates range-based IDs of embeddings to content-hash based IDs.
+    ///
+    /// Renames the embedding files and updates the manifest. Rebuild the
+    /// database afterward.
+    MigrateIds {
+        /// Directory where embedding results are stored.
+        dir: String,
+        /// Path to a JSON file where the mapping from old IDs to new IDs is
+        /// to be saved.
+        map_file: String,
+    },
+    /// Segments a Markdown text into sentences and traces the process.
+    ///
+    /// Useful to debug a badly segmented post.
+    Segment {
+        /// Path to the Markdown file to be segmented.
+        ///
+        /// Reads the standard input if omitted or "-".
+        input: Option<String>,
+        /// How a line break in a paragraph is treated.
+        ///
+        /// One of "whitespace", "boundary", or "auto".
+        #[arg(long, default_value = "whitespace")]
+        line_breaks: LineBreakMode,
+        /// Whether to output the results in JSON.
+        ///
+        /// JSON output is suitable for golden-file comparisons.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Fits the IDF weights of local embeddings to embedding results.
+    ///
+    /// Counts the document frequencies of the contents of the embeddings in
+    /// a directory; e.g., the results of `create --provider local`. Give the
+    /// saved weights to `--local-idf`, and run create again with `--full`.
+    FitIdf {
+        /// Directory where embedding results are stored.
+        in_dir: String,
+        /// Path to a JSON file where the weights are to be saved.
+        out_path: String,
+        /// Number of dimensions of local embeddings.
+        #[arg(long, default_value_t = DEFAULT_LOCAL_DIMENSIONS)]
+        local_dimensions: usize,
     },
 }
 
@@ -79,105 +388,867 @@ enum Commands {
 async fn main() -> Result<(), Error> {
     let cli = Cli::parse();
     match cli.command {
-        Commands::Create { username, out_dir } => {
-            create(username, out_dir).await?;
+        Commands::Create {
+            username,
+            out_dir,
+            source,
+            all_users,
+            concurrency,
+            source_path,
+            source_base_url,
+            remote_request_interval,
+            remote_cursor,
+            since,
+            until,
+            include_non_public,
+            replies,
+            filter_sentences,
+            min_chars,
+            min_tokens,
+            min_letter_ratio,
+            stop_list,
+            line_breaks,
+            context_before,
+            context_after,
+            context_title,
+            context_thread,
+            granularity,
+            cache_dir,
+            no_cache,
+            id_scheme,
+            full,
+            provider,
+        } => {
+            let mut filter = if filter_sentences {
+                SentenceFilter::recommended()
+            } else {
+                SentenceFilter::default()
+            };
+            if let Some(min_chars) = min_chars {
+                filter.min_chars = min_chars;
+            }
+            if let Some(min_tokens) = min_tokens {
+                filter.min_tokens = min_tokens;
+            }
+            if let Some(min_letter_ratio) = min_letter_ratio {
+                filter.min_letter_ratio = min_letter_ratio;
+            }
+            if let Some(stop_list) = stop_list {
+                let stop_list = read_to_string(stop_list)?;
+                filter = filter.with_stop_list(stop_list.lines());
+            }
+            let options = CreateOptions {
+                post_filter: PostFilter {
+                    since,
+                    until,
+                    public_only: !include_non_public,
+                    replies,
+                },
+                filter,
+                line_break_mode: line_breaks,
+                context_window: ContextWindow {
+                    before: context_before,
+                    after: context_after,
+                    title: context_title,
+                },
+                context_thread,
+                granularities: granularity,
+                cache_dir: if no_cache {
+                    None
+                } else {
+                    Some(cache_dir.unwrap_or(format!("{}/.cache", out_dir)))
+                },
+                id_scheme,
+                full,
+            };
+            let provider = embedding_provider(&provider)?;
+            if all_users {
+                if !matches!(source, SourceKind::S3) {
+                    bail!("--all-users is available only for the s3 source");
+                }
+                create_all_users(
+                    out_dir,
+                    provider.as_ref(),
+                    &options,
+                    concurrency,
+                ).await?;
+                return Ok(());
+            }
+            let source: Box<dyn PostSource> = match source {
+                SourceKind::S3 => {
+                    let username = username.context(
+                        "no username given for the s3 source",
+                    )?;
+                    let objects_bucket_name = env::var("OBJECTS_BUCKET_NAME")
+                        .context("no OBJECTS_BUCKET_NAME set")?;
+                    println!("objects bucket name: {}", objects_bucket_name);
+                    println!("pulling mumblings of {}", username);
+                    Box::new(
+                        S3PostSource::new(objects_bucket_name, username).await,
+                    )
+                },
+                SourceKind::Dir => Box::new(DirectoryPostSource::new(
+                    source_path.context("no --source-path given")?,
+                )),
+                SourceKind::Outbox => Box::new(OutboxPostSource::new(
+                    source_path.context("no --source-path given")?,
+                )),
+                SourceKind::Mastodon => Box::new(MastodonArchiveSource::new(
+                    source_path.context("no --source-path given")?,
+                )),
+                SourceKind::Misskey => Box::new(MisskeyNotesSource::new(
+                    source_path.context("no --source-path given")?,
+                    source_base_url.context("no --source-base-url given")?,
+                )),
+                SourceKind::Remote => {
+                    let mut source = RemoteOutboxSource::new(
+                        source_path.context("no --source-path given")?,
+                    )
+                        .with_request_interval(
+                            Duration::from_millis(remote_request_interval),
+                        );
+                    if let Some(remote_cursor) = remote_cursor {
+                        source = source.with_cursor_path(remote_cursor);
+                    }
+                    Box::new(source)
+                },
+            };
+            create(source.as_ref(), provider.as_ref(), out_dir, &options)
+                .await?;
+        },
+        Commands::Build {
+            in_dir,
+            out_dir,
+            test_query,
+            s3,
+            skip_orphans,
+            cw_policy,
+            provider,
+        } => {
+            let provider = match test_query {
+                Some(_) => Some(embedding_provider(&provider)?),
+                None => None,
+            };
+            build(
+                in_dir,
+                out_dir,
+                test_query.zip(provider.as_deref()),
+                s3,
+                skip_orphans,
+                cw_policy,
+            ).await?;
+        },
+        Commands::Query {
+            db_path,
+            query_text,
+            s3,
+            embedding_dir,
+            granularity,
+            include_cw,
+            since,
+            until,
+            recency_decay,
+            provider,
+        } => {
+            let options = QueryOptions {
+                granularity,
+                include_cw,
+                time_filter: TimeFilter { since, until, recency_decay },
+            };
+            let provider = embedding_provider(&provider)?;
+            query(
+                db_path,
+                query_text,
+                provider.as_ref(),
+                s3,
+                embedding_dir,
+                options,
+            ).await?;
+        },
+        Commands::MigrateIds { dir, map_file } => {
+            migrate_ids(dir, map_file)?;
         },
-        Commands::Build { in_dir, out_dir, test_query, s3 } => {
-            build(in_dir, out_dir, test_query, s3).await?;
+        Commands::Segment { input, line_breaks, json } => {
+            segment(input, line_breaks, json)?;
         },
-        Commands::Query { db_path, query_text, s3, embedding_dir } => {
-            query(db_path, query_text, s3, embedding_dir).await?;
+        Commands::FitIdf { in_dir, out_path, local_dimensions } => {
+            fit_idf(in_dir, out_path, local_dimensions)?;
         },
     }
     Ok(())
 }
 
-async fn create(username: String, out_dir: String) -> Result<(), Error> {
-    let objects_bucket_name = env::var("OBJECTS_BUCKET_NAME")
-        .context("no OBJECTS_BUCKET_NAME set")?;
-    println!("objects bucket name: {}", objects_bucket_name);
-    let openai_api_key = env::var("OPENAI_API_KEY")
-        .context("no OPENAI_API_KEY set")?;
+// Arguments to choose the provider of embeddings.
+#[derive(Args)]
+struct ProviderArgs {
+    /// Provider of embeddings.
+    ///
+    /// One of "openai", "local", or "onnx". "openai" needs OPENAI_API_KEY
+    /// environment variable unless `--openai-config` says no authentication,
+    /// and also works with Azure OpenAI and compatible servers. "local"
+    /// makes embeddings offline by feature hashing of word and character
+    /// n-grams, which capture lexical rather than semantic similarity.
+    /// "onnx" runs the sentence-transformer in `--onnx-model-dir` on the CPU,
+    /// and is available only if built with the "onnx" feature. Use the same
+    /// provider for all the commands.
+    #[arg(long, default_value = "openai")]
+    provider: ProviderKind,
+    /// Number of dimensions of local embeddings.
+    #[arg(long, default_value_t = DEFAULT_LOCAL_DIMENSIONS)]
+    local_dimensions: usize,
+    /// Path to the IDF weights of local embeddings saved by `fit-idf`.
+    ///
+    /// Features are weighted only by their counts if omitted.
+    #[arg(long)]
+    local_idf: Option<String>,
+    /// Maximum number of retries of a failed request to the OpenAI API.
+    ///
+    /// Only a rate-limited request, a server error, and a reset connection
+    /// are retried.
+    #[arg(long, default_value_t = 5)]
+    openai_max_retries: usize,
+    /// Maximum delay in seconds of a retry unless the OpenAI API asks for a
+    /// longer one.
+    #[arg(long, default_value_t = 60)]
+    openai_max_retry_delay: u64,
+    /// Path to a JSON file that configures the client of the OpenAI API.
+    ///
+    /// May have "baseUrl", "authScheme" ("bearer", "api-key", or "none"),
+    /// "apiVersion", "organization", "project", "timeout" in seconds,
+    /// "model", and "dimensions". "model" must tell the model of an Azure
+    /// OpenAI deployment. Read from OPENAI_BASE_URL, OPENAI_AUTH_SCHEME,
+    /// OPENAI_API_VERSION, OPENAI_ORGANIZATION, OPENAI_PROJECT,
+    /// OPENAI_TIMEOUT, OPENAI_EMBEDDING_MODEL, and
+    /// OPENAI_EMBEDDING_DIMENSIONS environment variables if omitted.
+    #[arg(long)]
+    openai_config: Option<String>,
+    /// Directory that contains "model.onnx" and "tokenizer.json" of a
+    /// sentence-transformer.
+    ///
+    /// Required if the provider is "onnx".
+    #[arg(long)]
+    onnx_model_dir: Option<String>,
+    /// Maximum number of tokens in an input to the ONNX model.
+    ///
+    /// Longer inputs are truncated.
+    #[arg(long, default_value_t = 256)]
+    onnx_max_tokens: usize,
+}
+
+// Kind of the provider of embeddings.
+#[derive(Clone, Copy)]
+enum ProviderKind {
+    OpenAi,
+    Local,
+    Onnx,
+}
+
+impl FromStr for ProviderKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "openai" => Ok(ProviderKind::OpenAi),
+            "local" => Ok(ProviderKind::Local),
+            "onnx" => Ok(ProviderKind::Onnx),
+            _ => bail!("unknown provider: {}", s),
+        }
+    }
+}
+
+// Returns the provider of embeddings.
+fn embedding_provider(
+    args: &ProviderArgs,
+) -> Result<Box<dyn EmbeddingProvider>, Error> {
+    match args.provider {
+        ProviderKind::OpenAi => {
+            let retry_policy = RetryPolicy {
+                max_retries: args.openai_max_retries,
+                max_delay: Duration::from_secs(args.openai_max_retry_delay),
+                ..RetryPolicy::default()
+            };
+            let config = match args.openai_config.as_ref() {
+                Some(path) => ClientConfig::load(path)?,
+                None => ClientConfig::from_env()?,
+            };
+            println!("OpenAI API: {}", config.base_url);
+            let provider = OpenAiEmbeddingProvider::from_env_with_config(
+                config,
+            )?.with_retry_policy(retry_policy);
+            println!(
+                "OpenAI embedding model: {} ({} dimensions)",
+                provider.model_id(),
+                provider.dimensions(),
+            );
+            Ok(Box::new(provider))
+        },
+        ProviderKind::Local => {
+            if args.local_dimensions == 0 {
+                bail!("--local-dimensions must be positive");
+            }
+            let mut provider =
+                LocalEmbeddingProvider::new(args.local_dimensions);
+            if let Some(path) = args.local_idf.as_ref() {
+                provider = provider.with_idf(IdfWeights::load(path)?)?;
+            }
+            println!("local embedding model: {}", provider.model_id());
+            Ok(Box::new(provider))
+        },
+        #[cfg(feature = "onnx")]
+        ProviderKind::Onnx => {
+            let model_dir = args.onnx_model_dir
+                .as_ref()
+                .context("no --onnx-model-dir given")?;
+            println!("loading ONNX model: {}", model_dir);
+            let provider =
+                OnnxEmbeddingProvider::load(model_dir, args.onnx_max_tokens)?;
+            println!(
+                "ONNX embedding model: {} ({} dimensions)",
+                provider.model_id(),
+                provider.dimensions(),
+            );
+            Ok(Box::new(provider))
+        },
+        #[cfg(not(feature = "onnx"))]
+        ProviderKind::Onnx => bail!("built without the onnx feature"),
+    }
+}
+
+// Kind of the source of posts.
+#[derive(Clone, Copy)]
+enum SourceKind {
+    S3,
+    Dir,
+    Outbox,
+    Mastodon,
+    Misskey,
+    Remote,
+}
+
+impl FromStr for SourceKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s3" => Ok(SourceKind::S3),
+            "dir" => Ok(SourceKind::Dir),
+            "outbox" => Ok(SourceKind::Outbox),
+            "mastodon" => Ok(SourceKind::Mastodon),
+            "misskey" => Ok(SourceKind::Misskey),
+            "remote" => Ok(SourceKind::Remote),
+            _ => bail!("unknown source: {}", s),
+        }
+    }
+}
+
+// Options for the create command.
+struct CreateOptions {
+    post_filter: PostFilter,
+    filter: SentenceFilter,
+    line_break_mode: LineBreakMode,
+    context_window: ContextWindow,
+    context_thread: bool,
+    granularities: Vec<Granularity>,
+    cache_dir: Option<String>,
+    id_scheme: IdScheme,
+    full: bool,
+}
+
+impl CreateOptions {
+    // Returns the options to be recorded in the manifest.
+    //
+    // Embeddings created with different ones must not be mixed. The post
+    // filter is included, because unchanged objects are not loaded and
+    // would never be checked against a new filter.
+    fn to_manifest_options(
+        &self,
+        provider: &dyn EmbeddingProvider,
+    ) -> BTreeMap<String, String> {
+        [
+            ("model", provider.model_id().to_string()),
+            ("postFilter", format!("{:?}", self.post_filter)),
+            ("granularities", format!("{:?}", self.granularities)),
+            ("idScheme", format!("{:?}", self.id_scheme)),
+            ("lineBreakMode", format!("{:?}", self.line_break_mode)),
+            ("sentenceFilter", format!("{:?}", self.filter)),
+            ("contextWindow", format!("{:?}", self.context_window)),
+            ("contextThread", format!("{:?}", self.context_thread)),
+        ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+}
+
+// Summary of a create run.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateSummary {
+    num_new: usize,
+    num_edited: usize,
+    num_deleted: usize,
+    num_unchanged: usize,
+    num_excluded: usize,
+    // objects skipped without being loaded
+    num_skipped_objects: usize,
+    // units embedded; i.e., sentences, text blocks, and so on
+    num_units: usize,
+    // estimated tokens in the embedding inputs including cached ones
+    num_tokens: usize,
+    // posts that failed to load, and failures of listing
+    num_failures: usize,
+    // error that stopped the run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn create(
+    source: &dyn PostSource,
+    provider: &dyn EmbeddingProvider,
+    out_dir: String,
+    options: &CreateOptions,
+) -> Result<CreateSummary, Error> {
     println!("output directory: {}", out_dir);
     if !Path::new(&out_dir).exists() {
         create_dir_all(&out_dir)?;
     }
-    println!("pulling mumblings of {}", username);
-    let posts = list_posts(&objects_bucket_name, &username).await;
-    let mut embeddings = posts
-        .map(|post| {
-            if let Ok(post) = post {
-                Ok(split_post_into_sentences(post))
-            } else {
-                Err(mumble_embedding::error::Error::InvalidData(
-                    format!("failed to list posts"),
-                ))
-            }
-        })
-        .flatten_results()
+    let mut manifest = Manifest::load(&out_dir)?;
+    let manifest_options = options.to_manifest_options(provider);
+    let full = if options.full {
+        true
+    } else if manifest.has_different_options(&manifest_options) {
+        println!("options changed since the last run: processing all posts");
+        true
+    } else {
+        false
+    };
+    // tells before listing, because a listing may change the source state
+    let mut is_complete_listing = source.is_complete_listing();
+    let cache = match options.cache_dir.as_ref() {
+        Some(cache_dir) => {
+            println!("embedding cache: {}", cache_dir);
+            Some(EmbeddingCache::open(cache_dir)?)
+        },
+        None => None,
+    };
+    // skips loading objects unchanged since the last run
+    let object_index = manifest.object_index();
+    let num_skipped_objects = Cell::new(0);
+    // remembers all the listed objects to detect deleted ones
+    let listed_keys: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // lists all the posts first, because a reply may precede its parent
+    let posts: Vec<_> = source.list_posts_except(Box::new(|origin| {
+        listed_keys.borrow_mut().insert(origin.key.clone());
+        let skipped = !full && object_index.is_unchanged(origin);
+        if skipped {
+            println!("skipping unchanged object: {}", origin.key);
+            num_skipped_objects.set(num_skipped_objects.get() + 1);
+        }
+        skipped
+    })).collect().await;
+    // resolves parents among posts processed in this or earlier runs
+    let mut threads = ThreadIndex::new();
+    for (post_id, entry) in manifest.posts.iter() {
+        if entry.snippet.is_some() {
+            threads.insert(post_id.clone(), ThreadEntry {
+                in_reply_to: entry.in_reply_to.clone(),
+                snippet: entry.snippet.clone(),
+            });
+        }
+    }
+    let mut summary = CreateSummary {
+        num_skipped_objects: num_skipped_objects.get(),
+        ..CreateSummary::default()
+    };
+    // posts processed in this run and their changes
+    let mut changes: Vec<(Post, PostChange)> = Vec::new();
+    for post in posts {
+        let post = match post {
+            Ok(post) => post,
+            Err(e) if e.is_post_load_error() => {
+                println!("{}", e);
+                summary.num_failures += 1;
+                continue;
+            },
+            Err(e) => {
+                println!("failed to list posts: {}", e);
+                summary.num_failures += 1;
+                is_complete_listing = false;
+                continue;
+            },
+        };
+        let change = match manifest.change_of(&post) {
+            PostChange::Deleted => PostChange::Deleted,
+            _ if !options.post_filter.matches(&post) => PostChange::Excluded,
+            PostChange::Unchanged if full => PostChange::Edited,
+            change => change,
+        };
+        match change {
+            // never gives an excluded post to replies as context
+            PostChange::Deleted | PostChange::Excluded => {
+                threads.remove(&post.id);
+            },
+            _ => threads.insert_post(&post, options.line_break_mode),
+        };
+        changes.push((post, change));
+    }
+    let mut units: Vec<PostSentence> = Vec::new();
+    for (post, change) in changes.iter() {
+        if !matches!(change, PostChange::New | PostChange::Edited) {
+            continue;
+        }
+        let filtered = options.filter.apply(split_post(
+            post,
+            &options.granularities,
+            options.line_break_mode,
+        ));
+        if filtered.num_dropped > 0 {
+            println!(
+                "dropped {} sentence(s) of {}",
+                filtered.num_dropped,
+                post.id,
+            );
+        }
+        let sentences = attach_context(
+            post,
+            filtered.sentences,
+            &options.context_window,
+        );
+        let parent_snippet = if options.context_thread {
+            threads.parent_snippet(post)
+        } else {
+            None
+        };
+        units.extend(attach_thread(
+            sentences,
+            &threads.thread_root(post),
+            parent_snippet,
+        ));
+    }
+    summary.num_units = units.len();
+    summary.num_tokens = units
+        .iter()
+        .map(|unit| estimate_tokens(&unit.embedding_input()))
+        .sum();
+    let mut embeddings = stream::iter(units)
         .chunks(10)
-        .then(|s| async {
-            if let Ok(s) = s.into_iter().collect::<Result<_, _>>() {
-                create_embeddings_for_sentences(s, openai_api_key.clone()).await
-            } else {
-                Err(mumble_embedding::error::Error::InvalidData(
-                    format!("failed to create embeddings for a batch"),
-                ))
-            }
-        })
+        .then(|s| create_embeddings_for_sentences(
+            s,
+            provider,
+            options.id_scheme,
+            cache.as_ref(),
+        ))
         .flatten_results();
+    // names of embedding files created for each post
+    let mut files: HashMap<String, Vec<String>> = HashMap::new();
+    let mut failure: Option<Error> = None;
     while let Some(embedding) = embeddings.next().await {
-        match embedding {
-            Ok(embedding) => {
-                println!("created embeddings: {:?}", embedding.id);
-                let parsed = Url::parse(&embedding.id)?;
-                let name = parsed.path_segments()
-                    .ok_or(anyhow!("invalid ID: {}", embedding.id))?
-                    .last()
-                    .ok_or(anyhow!("invalid ID: {}", embedding.id))?;
-                let fragment = parsed.fragment()
-                    .map_or("".to_string(), |f| format!("#{}", f));
-                let name = format!("{}{}", name, fragment);
-                let path = Path::new(&out_dir)
-                    .join(name)
-                    .with_extension("json");
-                println!("saving embedding to {:?}", path);
-                let out = File::create(path)?;
-                serde_json::to_writer(out, &embedding)?;
+        let saved = embedding.map_err(Error::from).and_then(|embedding| {
+            println!("created embeddings: {:?}", embedding.id);
+            let name = format!("{}.json", get_unique_part(&embedding.id)?);
+            let path = Path::new(&out_dir).join(&name);
+            println!("saving embedding to {:?}", path);
+            // records the file first, because a failed write may leave it
+            files.entry(get_post_id(&embedding.id).to_string())
+                .or_default()
+                .push(name);
+            let out = File::create(path)?;
+            serde_json::to_writer(out, &embedding)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            failure = Some(e);
+            break;
+        }
+    }
+    drop(embeddings);
+    // the manifest is saved only after a successful run, so files that it
+    // does not list would be orphans. files that it lists are overwritten,
+    // but their posts are still regarded as changed next time.
+    if let Some(e) = failure {
+        let listed_files = manifest.file_names();
+        let written_files = files
+            .values()
+            .flatten()
+            .filter(|name| !listed_files.contains(name.as_str()));
+        remove_embedding_files(&out_dir, written_files)?;
+        return Err(e);
+    }
+
+    // updates the manifest and removes stale embedding files
+    for (post, change) in changes {
+        let old_files = manifest.posts
+            .remove(&post.id)
+            .map(|entry| entry.files)
+            .unwrap_or_default();
+        let new_files = match change {
+            PostChange::Unchanged => {
+                println!("unchanged post: {}", post.id);
+                summary.num_unchanged += 1;
+                old_files
+            },
+            PostChange::Deleted => {
+                // keeps the entry to skip the Tombstone next time
+                if !old_files.is_empty() {
+                    println!("deleted post: {}", post.id);
+                    summary.num_deleted += 1;
+                    remove_embedding_files(&out_dir, &old_files)?;
+                }
+                Vec::new()
+            },
+            PostChange::Excluded => {
+                // keeps the entry to skip the object next time
+                println!("excluded post: {}", post.id);
+                summary.num_excluded += 1;
+                remove_embedding_files(&out_dir, &old_files)?;
+                Vec::new()
+            },
+            PostChange::New | PostChange::Edited => {
+                if change == PostChange::New {
+                    println!("new post: {}", post.id);
+                    summary.num_new += 1;
+                } else {
+                    println!("edited post: {}", post.id);
+                    summary.num_edited += 1;
+                }
+                let new_files = files.remove(&post.id).unwrap_or_default();
+                let stale_files = old_files
+                    .iter()
+                    .filter(|f| !new_files.contains(f));
+                remove_embedding_files(&out_dir, stale_files)?;
+                new_files
             },
-            err => {
-                err?;
-            }
         };
+        let mut entry = ManifestEntry::new(&post, new_files);
+        if !matches!(change, PostChange::Deleted | PostChange::Excluded) {
+            entry.snippet = threads
+                .get(&post.id)
+                .and_then(|thread| thread.snippet.clone());
+        }
+        manifest.posts.insert(post.id.clone(), entry);
+    }
+    // removes posts whose objects no longer exist
+    if !is_complete_listing {
+        println!("incomplete listing: keeping posts missing from it");
+    } else {
+        let listed_keys = listed_keys.borrow();
+        for (post_id, entry) in manifest.remove_missing(&listed_keys) {
+            println!("deleted post: {}", post_id);
+            summary.num_deleted += 1;
+            remove_embedding_files(&out_dir, &entry.files)?;
+        }
+    }
+    manifest.options = manifest_options;
+    manifest.save(&out_dir)?;
+    println!(
+        concat!(
+            "new: {}, edited: {}, deleted: {}, unchanged: {}, excluded: {}",
+            " (+{} object(s) not loaded)",
+        ),
+        summary.num_new,
+        summary.num_edited,
+        summary.num_deleted,
+        summary.num_unchanged,
+        summary.num_excluded,
+        summary.num_skipped_objects,
+    );
+    if let Some(cache) = cache.as_ref() {
+        println!(
+            "embedding cache: {} hit(s), {} miss(es), hit rate {:.1}%",
+            cache.hits(),
+            cache.misses(),
+            cache.hit_rate() * 100.0,
+        );
+    }
+    Ok(summary)
+}
+
+// Creates embeddings for the posts of all the users in the objects bucket.
+//
+// Processes at most `concurrency` users at the same time. A failure of a user
+// does not stop the others, and is recorded in the summary.
+async fn create_all_users(
+    out_dir: String,
+    provider: &dyn EmbeddingProvider,
+    options: &CreateOptions,
+    concurrency: usize,
+) -> Result<(), Error> {
+    let objects_bucket_name = env::var("OBJECTS_BUCKET_NAME")
+        .context("no OBJECTS_BUCKET_NAME set")?;
+    println!("objects bucket name: {}", objects_bucket_name);
+    let usernames = list_s3_usernames(&objects_bucket_name).await?;
+    println!("found {} user(s)", usernames.len());
+    // no user can be processed once the quota of the API runs out
+    let quota_exhausted = Cell::new(false);
+    let summaries: BTreeMap<String, CreateSummary> = stream::iter(usernames)
+        .map(|username| {
+            let bucket_name = objects_bucket_name.clone();
+            let user_dir = format!("{}/{}", out_dir, username);
+            let quota_exhausted = &quota_exhausted;
+            async move {
+                if quota_exhausted.get() {
+                    println!("skipping {}: quota exhausted", username);
+                    return (username, CreateSummary {
+                        error: Some("skipped: quota exhausted".to_string()),
+                        ..CreateSummary::default()
+                    });
+                }
+                println!("pulling mumblings of {}", username);
+                let source = S3PostSource::new(bucket_name, &username).await;
+                let summary = create(&source, provider, user_dir, options)
+                    .await
+                    .unwrap_or_else(|e| {
+                        println!("failed to process {}: {}", username, e);
+                        let is_insufficient_quota = e
+                            .downcast_ref::<EmbeddingError>()
+                            .is_some_and(|e| e.is_insufficient_quota());
+                        if is_insufficient_quota {
+                            quota_exhausted.set(true);
+                        }
+                        CreateSummary {
+                            error: Some(e.to_string()),
+                            ..CreateSummary::default()
+                        }
+                    });
+                (username, summary)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    for (username, summary) in summaries.iter() {
+        println!(
+            concat!(
+                "{}: new: {}, edited: {}, deleted: {}, unchanged: {},",
+                " excluded: {}, units: {}, tokens: ~{}, failures: {}{}",
+            ),
+            username,
+            summary.num_new,
+            summary.num_edited,
+            summary.num_deleted,
+            summary.num_unchanged,
+            summary.num_excluded,
+            summary.num_units,
+            summary.num_tokens,
+            summary.num_failures,
+            summary.error
+                .as_ref()
+                .map(|e| format!(", error: {}", e))
+                .unwrap_or_default(),
+        );
+    }
+    let path = Path::new(&out_dir).join("summary.json");
+    println!("saving summary to {:?}", path);
+    serde_json::to_writer_pretty(File::create(path)?, &summaries)?;
+    let num_failed_users = summaries.values()
+        .filter(|summary| summary.error.is_some())
+        .count();
+    if num_failed_users > 0 {
+        bail!("failed to process {} user(s)", num_failed_users);
+    }
+    Ok(())
+}
+
+// Removes given embedding files in a directory.
+//
+// Ignores missing files.
+fn remove_embedding_files<'a>(
+    dir: &str,
+    files: impl IntoIterator<Item = &'a String>,
+) -> Result<(), Error> {
+    for file in files {
+        println!("removing embedding: {}", file);
+        let path = Path::new(dir).join(file);
+        if path.exists() {
+            remove_file(path)?;
+        }
     }
     Ok(())
 }
 
+// Makes a test query with the provider if `test_query` is given.
 async fn build(
     in_dir: String,
     out_dir: String,
-    test_query: Option<String>,
+    test_query: Option<(String, &dyn EmbeddingProvider)>,
     s3: bool,
+    skip_orphans: bool,
+    cw_policy: CwPolicy,
 ) -> Result<(), Error> {
     const RESERVED_VECTORS: usize = 1000;
-    const VECTOR_SIZE: usize = 1536; // OpenAI embedding vector
     const NUM_PARTITIONS: usize = 1;
     const NUM_DIVISIONS: usize = 12;
     const NUM_CODES: usize = 10;
     let mut embeddings: Vec<Embedding> = Vec::with_capacity(RESERVED_VECTORS);
-    let mut data: Vec<f32> = Vec::with_capacity(RESERVED_VECTORS * VECTOR_SIZE);
-    for entry in read_dir(in_dir)? {
+    let mut data: Vec<f32> = Vec::new();
+    // embeddings are not checked if there is no manifest
+    let manifest = if Path::new(&in_dir).join(MANIFEST_FILE_NAME).exists() {
+        Some(Manifest::load(&in_dir)?)
+    } else {
+        println!("no manifest: orphaned embeddings are not detected");
+        None
+    };
+    let file_names = manifest.as_ref().map(Manifest::file_names);
+    let mut orphans: Vec<String> = Vec::new();
+    for entry in read_dir(&in_dir)? {
         let entry = entry?;
+        if !is_embedding_file(&entry)? {
+            continue;
+        }
+        if let Some(file_names) = file_names.as_ref() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !file_names.contains(name.as_str()) {
+                println!("WARNING: orphaned embedding: {}", name);
+                orphans.push(name);
+                continue;
+            }
+        }
         println!("loading: {:?}", entry.file_name());
         let file = File::open(entry.path())?;
-        let embedding: Embedding = serde_json::from_reader(file)?;
-        if embedding.embedding.len() != VECTOR_SIZE {
-            bail!("invalid vector size: {}", embedding.embedding.len());
+        let embedding = read_embedding(file)?;
+        // vectors of different models are not comparable
+        if let Some(first) = embeddings.first() {
+            if embedding.embedding.len() != first.embedding.len() {
+                bail!(
+                    "invalid vector size: {} ({}) but {} ({})",
+                    embedding.embedding.len(),
+                    embedding.id,
+                    first.embedding.len(),
+                    first.id,
+                );
+            }
+            if embedding.model != first.model {
+                bail!(
+                    "mixed embedding models: {} ({}) and {} ({})",
+                    first.model,
+                    first.id,
+                    embedding.model,
+                    embedding.id,
+                );
+            }
         }
-        data.extend(embedding.embedding.iter().map(|v| *v as f32));
+        data.extend_from_slice(&embedding.embedding);
         embeddings.push(embedding);
     }
-    let vs = BlockVectorSet::chunk(data, VECTOR_SIZE.try_into()?)?;
+    if !orphans.is_empty() {
+        if !skip_orphans {
+            bail!(
+                concat!(
+                    "{} orphaned embedding(s) found. run create again to",
+                    " prune them, or specify --skip-orphans to leave them out",
+                ),
+                orphans.len(),
+            );
+        }
+        println!("left out {} orphaned embedding(s)", orphans.len());
+    }
+    // the model determines the vector size
+    let vector_size = embeddings
+        .first()
+        .map(|embedding| embedding.embedding.len())
+        .context("no embeddings found")?;
+    println!("vector size: {}", vector_size);
+    let vs = BlockVectorSet::chunk(data, vector_size.try_into()?)?;
     let time = std::time::Instant::now();
     let mut db = DatabaseBuilder::new(vs)
         .with_partitions(NUM_PARTITIONS.try_into().unwrap())
@@ -187,29 +1258,52 @@ async fn build(
             println!("{:?} at {} s", event, time.elapsed().as_secs_f64());
         })?;
     println!("built database in {} μs", time.elapsed().as_micros());
-    // assigns content IDs to vectors
+    // assigns content IDs, models, granularities, search scopes, thread
+    // roots, source platforms, and published timestamps to vectors
     for (i, embedding) in embeddings.iter().enumerate() {
         db.set_attribute_at(i, ("content_id", embedding.id.clone()))?;
+        // a query must be embedded with the same model
+        db.set_attribute_at(i, ("model", embedding.model.clone()))?;
+        db.set_attribute_at(
+            i,
+            ("granularity", embedding.granularity.as_str().to_string()),
+        )?;
+        db.set_attribute_at(
+            i,
+            ("scope", embedding.search_scope(cw_policy).to_string()),
+        )?;
+        // falls back to the post itself for an embedding without a thread
+        let thread_root = embedding.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.thread_root.clone())
+            .unwrap_or_else(|| get_post_id(&embedding.id).to_string());
+        db.set_attribute_at(i, ("thread_root", thread_root))?;
+        // no source platform for a post on Mumble
+        let source_platform = embedding.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.source_platform.clone());
+        if let Some(source_platform) = source_platform {
+            db.set_attribute_at(i, ("source_platform", source_platform))?;
+        }
+        if let Some(published) = embedding.published() {
+            db.set_attribute_at(i, ("published", to_timestamp(&published)))?;
+        }
     }
 
     // makes a test query if one is given
-    if let Some(test_query) = test_query {
+    if let Some((test_query, provider)) = test_query {
         const K: usize = 10; // k-nearest neighbors
         const NPROBE: usize = 1;
-        let openai_api_key = env::var("OPENAI_API_KEY")
-            .context("no OPENAI_API_KEY set")?;
-        let query_embedding = create_embeddings(
-            &EmbeddingRequestBody {
-                model: "text-embedding-ada-002".to_string(),
-                input: vec![test_query.to_string()],
-                user: Some("mumble_embedding".to_string()),
-            },
-            openai_api_key,
-        ).await?;
-        let query_vector: Vec<f32> = query_embedding.data[0].embedding
-            .iter()
-            .map(|x| *x as f32)
-            .collect();
+        if let Some(embedding) = embeddings.first() {
+            if embedding.model != provider.model_id() {
+                bail!(
+                    "query model {} differs from database model {}",
+                    provider.model_id(),
+                    embedding.model,
+                );
+            }
+        }
+        let query_vector = embed_one(provider, &test_query).await?;
         let results = db.query_with_events(
             &query_vector,
             K.try_into()?,
@@ -262,24 +1356,14 @@ async fn build(
 async fn query(
     db_path: String,
     query_text: String,
+    provider: &dyn EmbeddingProvider,
     s3: bool,
     embedding_dir: Option<String>,
+    options: QueryOptions,
 ) -> Result<(), Error> {
     println!("creating embedding for the query");
-    let openai_api_key = env::var("OPENAI_API_KEY")
-        .context("no OPENAI_API_KEY set")?;
-    let query_embedding = create_embeddings(
-        &EmbeddingRequestBody {
-            model: "text-embedding-ada-002".to_string(),
-            input: vec![query_text.to_string()],
-            user: Some("mumble_embedding".to_string()),
-        },
-        openai_api_key,
-    ).await?;
-    let query_vector: Vec<f32> = query_embedding.data[0].embedding
-        .iter()
-        .map(|x| *x as f32)
-        .collect();
+    let query_vector = embed_one(provider, &query_text).await?;
+    let model_id = provider.model_id().to_string();
     let content_ids = if s3 {
         let bucket_name = env::var("DATABASE_BUCKET_NAME")
             .expect("no DATABASE_BUCKET_NAME set");
@@ -307,7 +1391,7 @@ async fn query(
             let db = Database::<f32, _>::load_database(fs, db_name)
                 .expect("failed to load database");
             println!("loaded database in {} μs", time.elapsed().as_micros());
-            let res = do_query(&db, &query_vector[..]);
+            let res = do_query(&db, &query_vector[..], &model_id, &options);
             tx.send(res)
                 .or(Err(anyhow::anyhow!("failed to return database")))
                 .unwrap();
@@ -324,7 +1408,7 @@ async fn query(
             db_path.file_name().unwrap().to_str().unwrap(),
         )?;
         println!("loaded database in {} μs", time.elapsed().as_micros());
-        do_query(&db, &query_vector[..])
+        do_query(&db, &query_vector[..], &model_id, &options)
     }?;
     if let Some(embedding_dir) = embedding_dir {
         for (i, id) in content_ids.iter().enumerate() {
@@ -332,16 +1416,34 @@ async fn query(
             let embedding_path = Path::new(&embedding_dir)
                 .join(format!("{}.json", &unique_part));
             let file = File::open(embedding_path)?;
-            let embedding: Embedding = serde_json::from_reader(file)?;
+            let embedding = read_embedding(file)?;
             println!("[{}]: {}", i, embedding.content);
         }
     }
     Ok(())
 }
 
+// Options of a query.
+#[derive(Clone, Copy, Debug, Default)]
+struct QueryOptions {
+    // only results of this granularity are returned if specified.
+    granularity: Option<Granularity>,
+    // only results in the default search scope are returned unless true.
+    include_cw: bool,
+    // bounds and recency decay of results.
+    time_filter: TimeFilter,
+}
+
+// Queries k-NN, and returns the content IDs of the results.
+//
+// Results are reranked by their scores if the time filter decays them.
+// Fails if the database was built with embeddings of a model other than
+// `model_id`.
 fn do_query<FS, V>(
     db: &Database<f32, FS>,
     query_vector: V,
+    model_id: &str,
+    options: &QueryOptions,
 ) -> Result<Vec<String>, Error>
 where
     FS: FileSystem,
@@ -349,20 +1451,108 @@ where
 {
     const K: usize = 10; // k-nearest neighbors
     const NPROBE: usize = 1;
+    // over-samples neighbors to filter by granularity, scope, and time
+    const OVERSAMPLING: usize = 5;
+    let QueryOptions { granularity, include_cw, time_filter } = *options;
+    let k = if granularity.is_some()
+        || !include_cw
+        || !time_filter.is_empty()
+    {
+        K * OVERSAMPLING
+    } else {
+        K
+    };
     // queries k-NN
     let time = std::time::Instant::now();
+    let now = now_timestamp();
     let results = db.query_with_events(
         query_vector.as_slice(),
-        K.try_into().unwrap(),
+        k.try_into().unwrap(),
         NPROBE.try_into().unwrap(),
         |event| {
             println!("{:?} at {} s", event, time.elapsed().as_secs_f64());
         },
     )?;
     println!("queried k-NN in {} μs", time.elapsed().as_micros());
+    // every vector has the same model; a database built before the model
+    // was recorded has none
+    if let Some(result) = results.first() {
+        let value = result
+            .get_attribute("model")
+            .map_err(|e| anyhow!("failed to get attribute: {}", e))?;
+        match value.as_deref() {
+            Some(AttributeValue::String(s)) if s != model_id => bail!(
+                "query model {} differs from database model {}",
+                model_id,
+                s,
+            ),
+            Some(AttributeValue::String(_)) => {},
+            Some(_) => bail!("model must be a string"),
+            None => println!("WARNING: no embedding model in the database"),
+        }
+    }
     let time = std::time::Instant::now();
-    let content_ids = results.into_iter()
+    let results = results.into_iter()
         .map(|result| {
+            if let Some(granularity) = granularity {
+                let value = result
+                    .get_attribute("granularity")
+                    .map_err(|e| anyhow!("failed to get attribute: {}", e))?;
+                // treats a missing granularity as a sentence
+                let matches = match value.as_deref() {
+                    Some(AttributeValue::String(s)) =>
+                        s == granularity.as_str(),
+                    Some(_) => bail!("granularity must be a string"),
+                    None => granularity == Granularity::Sentence,
+                };
+                if !matches {
+                    return Ok(None);
+                }
+            }
+            if !include_cw {
+                let value = result
+                    .get_attribute("scope")
+                    .map_err(|e| anyhow!("failed to get attribute: {}", e))?;
+                // treats a missing scope as the default
+                let is_cw = match value.as_deref() {
+                    Some(AttributeValue::String(s)) => s == CW_SCOPE,
+                    Some(_) => bail!("scope must be a string"),
+                    None => false,
+                };
+                if is_cw {
+                    return Ok(None);
+                }
+            }
+            let published = if time_filter.is_empty() {
+                None
+            } else {
+                let value = result
+                    .get_attribute("published")
+                    .map_err(|e| anyhow!("failed to get attribute: {}", e))?;
+                match value.as_deref() {
+                    Some(AttributeValue::Uint64(t)) => Some(*t),
+                    Some(_) => bail!("published must be a number"),
+                    None => None,
+                }
+            };
+            if !time_filter.matches(published) {
+                return Ok(None);
+            }
+            let score =
+                time_filter.score(result.squared_distance, published, now);
+            Ok(Some((result, score)))
+        })
+        .filter_map(|result| result.transpose());
+    let mut results = if time_filter.has_decay() {
+        results.collect::<Result<Vec<_>, Error>>()?
+    } else {
+        results.take(K).collect::<Result<Vec<_>, Error>>()?
+    };
+    // stable, so ties keep the order of distances
+    results.sort_by(|(_, lhs), (_, rhs)| rhs.total_cmp(lhs));
+    results.truncate(K);
+    let content_ids = results.into_iter()
+        .map(|(result, _)| {
             result
                 .get_attribute("content_id")
                 .map_err(|e| anyhow!("failed to get attribute: {}", e))
@@ -394,3 +1584,214 @@ fn get_unique_part<'a>(id: &str) -> Result<String, Error> {
         .unwrap_or("".to_string());
     Ok(format!("{}{}", part, fragment))
 }
+
+// Returns if a given directory entry is an embedding file.
+//
+// Excludes the manifest and subdirectories; e.g., the embedding cache.
+fn is_embedding_file(entry: &DirEntry) -> Result<bool, Error> {
+    Ok(entry.file_type()?.is_file() && entry.file_name() != MANIFEST_FILE_NAME)
+}
+
+// Returns the ID of the post that a given content ID belongs to.
+fn get_post_id(id: &str) -> &str {
+    id.split_once('#').map_or(id, |(post_id, _)| post_id)
+}
+
+fn fit_idf(
+    in_dir: String,
+    out_path: String,
+    local_dimensions: usize,
+) -> Result<(), Error> {
+    if local_dimensions == 0 {
+        bail!("--local-dimensions must be positive");
+    }
+    let mut contents: Vec<String> = Vec::new();
+    for entry in read_dir(&in_dir)? {
+        let entry = entry?;
+        if !is_embedding_file(&entry)? {
+            continue;
+        }
+        let embedding = read_embedding(File::open(entry.path())?)?;
+        contents.push(embedding.content);
+    }
+    println!("fitting IDF weights to {} content(s)", contents.len());
+    let weights = IdfWeights::fit(
+        contents.iter().map(String::as_str),
+        local_dimensions,
+    );
+    weights.save(&out_path)?;
+    println!("saved IDF weights to {}", out_path);
+    Ok(())
+}
+
+// Embedding with a range-based ID, its range, and file name.
+type RangeIdEmbedding = (Range<usize>, String, Embedding);
+
+fn migrate_ids(dir: String, map_file: String) -> Result<(), Error> {
+    let has_manifest = Path::new(&dir).join(MANIFEST_FILE_NAME).exists();
+    let mut manifest = Manifest::load(&dir)?;
+    // collects embeddings with range-based IDs by post and granularity
+    let mut groups: HashMap<(String, Granularity), Vec<RangeIdEmbedding>> =
+        HashMap::new();
+    for entry in read_dir(&dir)? {
+        let entry = entry?;
+        if !is_embedding_file(&entry)? {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file = File::open(entry.path())?;
+        let embedding = read_embedding(file)?;
+        let Some((post_id, granularity, range)) =
+            parse_range_id(&embedding.id) else { continue };
+        groups.entry((post_id.to_string(), granularity))
+            .or_default()
+            .push((range, name, embedding));
+    }
+    let mut id_map: BTreeMap<String, String> = BTreeMap::new();
+    // new names of embedding files keyed by old ones
+    let mut renamed_files: HashMap<String, String> = HashMap::new();
+    for ((post_id, granularity), mut embeddings) in groups {
+        // occurrences are counted in order of appearance
+        embeddings.sort_by_key(|(range, _, _)| range.start);
+        let mut occurrences: HashMap<String, usize> = HashMap::new();
+        for (range, old_name, mut embedding) in embeddings {
+            // the ID of the first occurrence identifies the content
+            let first_id =
+                make_content_id(&post_id, granularity, &embedding.content, 0);
+            let occurrence = occurrences.entry(first_id).or_default();
+            let new_id = make_content_id(
+                &post_id,
+                granularity,
+                &embedding.content,
+                *occurrence,
+            );
+            *occurrence += 1;
+            let new_name = format!("{}.json", get_unique_part(&new_id)?);
+            println!("migrating {} to {}", embedding.id, new_id);
+            id_map.insert(embedding.id.clone(), new_id.clone());
+            embedding.id = new_id;
+            embedding.range.get_or_insert(range);
+            let out = File::create(Path::new(&dir).join(&new_name))?;
+            serde_json::to_writer(out, &embedding)?;
+            if new_name != old_name {
+                remove_file(Path::new(&dir).join(&old_name))?;
+            }
+            renamed_files.insert(old_name, new_name);
+        }
+    }
+    if has_manifest {
+        for entry in manifest.posts.values_mut() {
+            for file in entry.files.iter_mut() {
+                if let Some(new_name) = renamed_files.get(file) {
+                    *file = new_name.clone();
+                }
+            }
+        }
+        if manifest.options.contains_key("idScheme") {
+            manifest.options.insert(
+                "idScheme".to_string(),
+                format!("{:?}", IdScheme::Content),
+            );
+        }
+        manifest.save(&dir)?;
+    }
+    println!("saving ID mapping to {}", map_file);
+    let out = File::create(map_file)?;
+    serde_json::to_writer_pretty(out, &id_map)?;
+    println!("migrated {} ID(s)", id_map.len());
+    Ok(())
+}
+
+// Segmentation trace of a text block.
+#[derive(Serialize)]
+struct TextBlockTrace {
+    text_block: TextBlock,
+    #[serde(flatten)]
+    trace: SegmentationTrace,
+}
+
+fn segment(
+    input: Option<String>,
+    line_break_mode: LineBreakMode,
+    json: bool,
+) -> Result<(), Error> {
+    let source = match input.as_deref() {
+        None | Some("-") => std::io::read_to_string(std::io::stdin())?,
+        Some(path) => read_to_string(path)?,
+    };
+    let traces: Vec<TextBlockTrace> = extract_text_blocks(&source)?
+        .into_iter()
+        .map(|text_block| TextBlockTrace {
+            trace: trace_sentences(&text_block, line_break_mode),
+            text_block,
+        })
+        .collect();
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &traces)?;
+        println!();
+        return Ok(());
+    }
+    for (i, TextBlockTrace { text_block, trace }) in traces.iter().enumerate() {
+        println!("text block[{}]:", i);
+        match text_block {
+            TextBlock::Text(fragments) => {
+                println!(
+                    "  line breaks as boundaries: {}",
+                    trace.line_break_as_boundary,
+                );
+                for (j, (content, range)) in fragments.iter().enumerate() {
+                    println!("  fragment[{}] {:?}: {:?}", j, range, content);
+                }
+                println!("  transitions:");
+                for transition in trace.transitions.iter() {
+                    let input = match &transition.input {
+                        TransitionInput::Character(ch) => format!("{:?}", ch),
+                        TransitionInput::LineBreak => "<line break>".into(),
+                        TransitionInput::String(s) => format!("{:?}", s),
+                        TransitionInput::End => "<end>".into(),
+                    };
+                    let output: Vec<String> = transition.output
+                        .iter()
+                        .map(|(token, range)| format!("{:?} {:?}", token, range))
+                        .collect();
+                    println!(
+                        "    @{} {}: {} -> {} [{}]",
+                        transition.index,
+                        input,
+                        transition.from,
+                        transition.to,
+                        output.join(", "),
+                    );
+                }
+            },
+            TextBlock::Code { language, range, .. } => {
+                println!("  code block {:?}: language={:?}", range, language);
+            },
+        }
+        println!("  sentences:");
+        for (j, (sentence, range)) in trace.sentences.iter().enumerate() {
+            println!("    [{}] {:?}: {:?}", j, range, sentence);
+            println!("        {}", highlight_range(&source, range));
+        }
+    }
+    Ok(())
+}
+
+// Highlights a given range in the source with the surrounding lines.
+//
+// Ranges that do not fall on character boundaries cannot be highlighted.
+fn highlight_range(source: &str, range: &Range<usize>) -> String {
+    let line_start = source.get(..range.start)
+        .map(|s| s.rfind('\n').map_or(0, |i| i + 1));
+    let line_end = source.get(range.end..)
+        .map(|s| s.find('\n').map_or(source.len(), |i| range.end + i));
+    match (line_start, source.get(range.clone()), line_end) {
+        (Some(line_start), Some(highlighted), Some(line_end)) => format!(
+            "{}\u{27E6}{}\u{27E7}{}",
+            &source[line_start..range.start],
+            highlighted,
+            &source[range.end..line_end],
+        ).escape_debug().to_string(),
+        _ => format!("(cannot highlight {:?} in the source)", range),
+    }
+}
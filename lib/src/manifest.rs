@@ -0,0 +1,292 @@
+//! Manifest of processed posts.
+//!
+//! A manifest is saved in the output directory of embeddings, and allows
+//! `create` to skip posts that have not changed since the last run.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::posts::{Post, PostOrigin};
+
+/// Name of the manifest file in an output directory.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Manifest of processed posts.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    /// Entries keyed by post ID.
+    pub posts: BTreeMap<String, ManifestEntry>,
+    /// Options that the embeddings were created with, keyed by name.
+    ///
+    /// Embeddings created with different options must not be mixed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Loads the manifest in a given directory.
+    ///
+    /// Returns an empty manifest if the directory has no manifest.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = dir.as_ref().join(MANIFEST_FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Saves the manifest in a given directory.
+    pub fn save(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let file = File::create(dir.as_ref().join(MANIFEST_FILE_NAME))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Returns if the recorded posts were processed with options different
+    /// from given ones.
+    ///
+    /// A manifest without posts has no conflicting options.
+    pub fn has_different_options(
+        &self,
+        options: &BTreeMap<String, String>,
+    ) -> bool {
+        !self.posts.is_empty() && self.options != *options
+    }
+
+    /// Indexes the objects of the recorded posts.
+    pub fn object_index(&self) -> ObjectIndex<'_> {
+        ObjectIndex {
+            objects: self.posts
+                .values()
+                .filter_map(|entry| entry.e_tag
+                    .as_deref()
+                    .map(|e_tag| (entry.key.as_str(), e_tag)))
+                .collect(),
+        }
+    }
+
+    /// Returns how a given post has changed since it was recorded.
+    ///
+    /// A `Tombstone` is always [`PostChange::Deleted`].
+    pub fn change_of(&self, post: &Post) -> PostChange {
+        if post.type_ == "Tombstone" {
+            return PostChange::Deleted;
+        }
+        match self.posts.get(&post.id) {
+            None => PostChange::New,
+            Some(entry) if entry.content_hash == post.content_hash() =>
+                PostChange::Unchanged,
+            Some(_) => PostChange::Edited,
+        }
+    }
+
+    /// Removes entries of posts whose objects are not in `keys`.
+    ///
+    /// `keys` must be the keys of all the objects currently listed.
+    /// Returns the removed entries keyed by post ID.
+    pub fn remove_missing(
+        &mut self,
+        keys: &HashSet<String>,
+    ) -> Vec<(String, ManifestEntry)> {
+        let missing: Vec<String> = self.posts
+            .iter()
+            .filter(|(_, entry)| !keys.contains(&entry.key))
+            .map(|(id, _)| id.clone())
+            .collect();
+        missing
+            .into_iter()
+            .filter_map(|id| self.posts.remove(&id).map(|entry| (id, entry)))
+            .collect()
+    }
+
+    /// Returns the names of the embedding files of the recorded posts.
+    pub fn file_names(&self) -> HashSet<&str> {
+        self.posts
+            .values()
+            .flat_map(|entry| entry.files.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// Index of the objects of recorded posts.
+///
+/// Made by [`Manifest::object_index`].
+pub struct ObjectIndex<'a> {
+    // pairs of key and ETag
+    objects: HashSet<(&'a str, &'a str)>,
+}
+
+impl ObjectIndex<'_> {
+    /// Returns if the object at a given origin is known to be unchanged.
+    ///
+    /// An object is unchanged if an entry has the same key and ETag.
+    /// Useful to skip loading unchanged objects.
+    pub fn is_unchanged(&self, origin: &PostOrigin) -> bool {
+        origin.e_tag.as_deref().is_some_and(|e_tag| {
+            self.objects.contains(&(origin.key.as_str(), e_tag))
+        })
+    }
+}
+
+/// Entry of a processed post.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    /// Key of the object where the post was loaded from.
+    pub key: String,
+    /// Published in RFC 3339.
+    ///
+    /// Empty if the post has no published date.
+    pub published: String,
+    /// Updated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+    /// ETag of the object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e_tag: Option<String>,
+    /// Hash of the content; see [`Post::content_hash`].
+    pub content_hash: String,
+    /// ID of the object that the post replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    /// Snippet of the post given to replies as context; see
+    /// [`crate::threads::snippet_of`].
+    ///
+    /// `None` for a post excluded from the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// Names of the embedding files created from the post.
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl ManifestEntry {
+    /// Creates an entry of a given post.
+    ///
+    /// `files` are names of the embedding files created from the post.
+    /// The snippet is left `None`.
+    pub fn new(post: &Post, files: Vec<String>) -> Self {
+        let origin = post.origin.as_ref();
+        Self {
+            key: origin.map(|o| o.key.clone()).unwrap_or_default(),
+            published: post.published
+                .map(|published| published.to_rfc3339())
+                .unwrap_or_default(),
+            updated: post.updated.clone(),
+            e_tag: origin.and_then(|o| o.e_tag.clone()),
+            content_hash: post.content_hash(),
+            in_reply_to: post.in_reply_to.clone(),
+            snippet: None,
+            files,
+        }
+    }
+}
+
+/// Change of a post since the last run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PostChange {
+    /// Post has not been processed.
+    New,
+    /// Content of the post has changed.
+    Edited,
+    /// Content of the post has not changed.
+    Unchanged,
+    /// Post has been deleted; i.e., `Tombstone`.
+    Deleted,
+    /// Post is excluded by a [`crate::posts::PostFilter`].
+    ///
+    /// Embeddings of the post are removed as if it were deleted.
+    Excluded,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_detects_changes_of_posts() {
+        let mut post = Post {
+            id: "https://example.com/posts/1".to_string(),
+            type_: "Note".to_string(),
+            content: "Hello, world.".to_string(),
+            published: "2023-09-20T00:00:00Z".parse().ok(),
+            origin: Some(PostOrigin {
+                key: "objects/users/test/posts/1.json".to_string(),
+                e_tag: Some("\"abc\"".to_string()),
+            }),
+            ..Post::default()
+        };
+        let mut manifest = Manifest::default();
+        assert_eq!(manifest.change_of(&post), PostChange::New);
+        manifest.posts.insert(
+            post.id.clone(),
+            ManifestEntry::new(&post, vec!["1#0-13.json".to_string()]),
+        );
+        assert_eq!(manifest.change_of(&post), PostChange::Unchanged);
+        let origin = post.origin.clone().unwrap();
+        assert!(manifest.object_index().is_unchanged(&origin));
+
+        post.content = "Hello, everyone.".to_string();
+        post.updated = Some("2023-09-21T00:00:00Z".to_string());
+        post.origin.as_mut().unwrap().e_tag = Some("\"def\"".to_string());
+        assert_eq!(manifest.change_of(&post), PostChange::Edited);
+        let origin = post.origin.clone().unwrap();
+        assert!(!manifest.object_index().is_unchanged(&origin));
+
+        // edits of the published time or parent are also changes
+        manifest.posts.insert(
+            post.id.clone(),
+            ManifestEntry::new(&post, vec!["1#0-16.json".to_string()]),
+        );
+        post.published = "2023-09-19T00:00:00Z".parse().ok();
+        assert_eq!(manifest.change_of(&post), PostChange::Edited);
+        post.published = "2023-09-20T00:00:00Z".parse().ok();
+        assert_eq!(manifest.change_of(&post), PostChange::Unchanged);
+        post.in_reply_to = Some("https://example.com/posts/0".to_string());
+        assert_eq!(manifest.change_of(&post), PostChange::Edited);
+
+        post.type_ = "Tombstone".to_string();
+        assert_eq!(manifest.change_of(&post), PostChange::Deleted);
+    }
+
+    #[test]
+    fn manifest_removes_posts_missing_from_listing() {
+        let entry = |key: &str, file: &str| ManifestEntry {
+            key: key.to_string(),
+            published: "2023-09-20T00:00:00Z".to_string(),
+            updated: None,
+            e_tag: None,
+            content_hash: String::new(),
+            in_reply_to: None,
+            snippet: None,
+            files: vec![file.to_string()],
+        };
+        let mut manifest = Manifest::default();
+        manifest.posts.insert("1".to_string(), entry("posts/1", "1.json"));
+        manifest.posts.insert("2".to_string(), entry("posts/2", "2.json"));
+        let keys: HashSet<String> = ["posts/1".to_string()].into();
+        let removed = manifest.remove_missing(&keys);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, "2");
+        assert_eq!(manifest.file_names(), ["1.json"].into());
+    }
+
+    #[test]
+    fn manifest_tells_different_options() {
+        let options: BTreeMap<String, String> =
+            [("idScheme".to_string(), "Range".to_string())].into();
+        let mut manifest = Manifest::default();
+        assert!(!manifest.has_different_options(&options));
+        manifest.posts.insert(
+            "1".to_string(),
+            ManifestEntry::new(&Post::default(), Vec::new()),
+        );
+        assert!(manifest.has_different_options(&options));
+        manifest.options = options.clone();
+        assert!(!manifest.has_different_options(&options));
+    }
+}
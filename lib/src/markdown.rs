@@ -2,11 +2,12 @@
 
 use core::ops::Range;
 use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+use serde::Serialize;
 
 use crate::error::Error;
 
 /// Text block in a Markdown text.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum TextBlock {
     /// Text block.
     Text(Vec<Fragment>),
@@ -25,7 +26,7 @@ pub enum TextBlock {
 ///
 /// Fragment type will matter in further segmentation; e.g., no sentence is
 /// split in the middle of a code fragment.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum FragmentContent {
     /// Ordinary text.
     Text(String),
@@ -82,6 +83,41 @@ pub fn extract_text_blocks(text: &str) -> Result<Vec<TextBlock>, Error> {
     extractor.finish()
 }
 
+/// Extracts headings in a given Markdown text.
+///
+/// Returns the text of each heading and its range in the input.
+pub fn extract_headings(
+    text: &str,
+) -> Result<Vec<(String, Range<usize>)>, Error> {
+    let parser = Parser::new_ext(
+        text,
+        Options::ENABLE_TABLES
+            | Options::ENABLE_STRIKETHROUGH
+            | Options::ENABLE_TASKLISTS,
+    );
+    let mut headings: Vec<(String, Range<usize>)> = Vec::new();
+    let mut current: Option<(String, Range<usize>)> = None;
+    for (event, range) in parser.into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading(_, _, _)) => {
+                current = Some((String::new(), range));
+            },
+            Event::End(Tag::Heading(_, _, _)) => {
+                if let Some((heading, range)) = current.take() {
+                    headings.push((heading.trim().to_string(), range));
+                }
+            },
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((heading, _)) = current.as_mut() {
+                    heading.push_str(&text);
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(headings)
+}
+
 #[derive(Debug)]
 struct TextBlockExtractor {
     state_stack: Vec<TextBlockExtractorState>,
@@ -409,7 +445,8 @@ impl TextBlockExtractorState {
             },
             Event::SoftBreak => {
                 // appends a line break to the last fragment
-                // unless it is a code fragment
+                // or pushes a new fragment if it is not a text fragment
+                // so that a line break after a code fragment survives
                 if let Some(last_text) = fragments
                     .last_mut()
                     .filter(|(f, _)| f.is_text())
@@ -418,6 +455,12 @@ impl TextBlockExtractorState {
                         "{}\n",
                         last_text.0.text(),
                     ));
+                    last_text.1.end = range.end;
+                } else if !fragments.is_empty() {
+                    fragments.push((
+                        FragmentContent::Text("\n".to_string()),
+                        range,
+                    ));
                 }
                 stack_again!();
                 Ok(())
@@ -633,4 +676,16 @@ mod tests {
             ]),
         ]);
     }
+
+    #[test]
+    fn extract_text_blocks_keeps_soft_break_after_inline_code() {
+        let input = "run `make`\nand wait";
+        assert_eq!(extract_text_blocks(input).unwrap(), vec![
+            TextBlock::Text(vec![
+                (FragmentContent::Text("run ".to_string()), 0..4),
+                (FragmentContent::Code("make".to_string()), 4..10),
+                (FragmentContent::Text("\nand wait".to_string()), 10..19),
+            ]),
+        ]);
+    }
 }
@@ -0,0 +1,275 @@
+//! Local embeddings by a sentence-transformer exported to ONNX.
+//!
+//! Available with the `onnx` feature. A model runs on the CPU, so contents
+//! never leave the machine.
+//!
+//! A model directory must contain:
+//! - `model.onnx`: the transformer that takes `input_ids`, `attention_mask`,
+//!   and optionally `token_type_ids`, and outputs the hidden states of tokens
+//!   first; e.g., `sentence-transformers/paraphrase-multilingual-MiniLM-L12-v2`
+//!   exported with Hugging Face Optimum.
+//! - `tokenizer.json`: the tokenizer of the model in the format of Hugging
+//!   Face Tokenizers.
+
+use futures::future::LocalBoxFuture;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use tokenizers::tokenizer::{Tokenizer, TruncationParams};
+use tract_onnx::prelude::{
+    Framework,
+    InferenceModelExt,
+    IntoTValue,
+    Tensor,
+    TypedRunnableModel,
+    TypedModel,
+};
+
+use crate::error::Error;
+use crate::providers::EmbeddingProvider;
+
+// Inputs of a model.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ModelInput {
+    InputIds,
+    AttentionMask,
+    TokenTypeIds,
+}
+
+/// Provider of embeddings by a sentence-transformer exported to ONNX.
+///
+/// The embedding of a text is the mean of the hidden states of its tokens,
+/// normalized to unit length.
+pub struct OnnxEmbeddingProvider {
+    model: TypedRunnableModel<TypedModel>,
+    inputs: Vec<ModelInput>,
+    tokenizer: Tokenizer,
+    model_id: String,
+    dimensions: usize,
+}
+
+impl OnnxEmbeddingProvider {
+    /// Loads the model in a given directory.
+    ///
+    /// Truncates inputs to `max_tokens`. The model ID is "onnx:" followed by
+    /// the name of the directory and the fingerprint of the model files and
+    /// `max_tokens`, because embeddings by different models or truncations
+    /// are not comparable. The dimensions are taken from the output of the
+    /// model.
+    pub fn load(
+        model_dir: impl AsRef<Path>,
+        max_tokens: usize,
+    ) -> Result<Self, Error> {
+        let model_dir = model_dir.as_ref();
+        let mut tokenizer = Tokenizer::from_file(
+            model_dir.join("tokenizer.json"),
+        ).map_err(onnx_error)?;
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: max_tokens,
+                ..TruncationParams::default()
+            }))
+            .map_err(onnx_error)?;
+        // padding is pointless for an input at a time
+        tokenizer.with_padding(None);
+        let model = tract_onnx::onnx()
+            .model_for_path(model_dir.join("model.onnx"))
+            .map_err(onnx_error)?;
+        let inputs = model.input_outlets()
+            .map_err(onnx_error)?
+            .iter()
+            .map(|outlet| match model.node(outlet.node).name.as_str() {
+                "input_ids" => Ok(ModelInput::InputIds),
+                "attention_mask" => Ok(ModelInput::AttentionMask),
+                "token_type_ids" => Ok(ModelInput::TokenTypeIds),
+                name => Err(Error::InvalidData(format!(
+                    "unsupported model input: {}",
+                    name,
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let model = model
+            .into_typed()
+            .and_then(|model| model.into_decluttered())
+            .and_then(|model| model.into_runnable())
+            .map_err(onnx_error)?;
+        let model_id = format!(
+            "onnx:{}-{}",
+            model_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            fingerprint(model_dir, max_tokens)?,
+        );
+        let mut provider = Self {
+            model,
+            inputs,
+            tokenizer,
+            model_id,
+            dimensions: 0,
+        };
+        // the hidden size is not always static in the graph
+        provider.dimensions = provider.embed_text("dimensions")?.len();
+        Ok(provider)
+    }
+
+    /// Replaces the model ID.
+    ///
+    /// The ID must change whenever the model files change.
+    pub fn with_model_id(mut self, model_id: impl Into<String>) -> Self {
+        self.model_id = model_id.into();
+        self
+    }
+
+    /// Embeds a given text.
+    pub fn embed_text(&self, text: &str) -> Result<Vec<f32>, Error> {
+        let encoding = self.tokenizer
+            .encode(text, true)
+            .map_err(onnx_error)?;
+        let num_tokens = encoding.get_ids().len();
+        let to_tensor = |values: &[u32]| {
+            let values: Vec<i64> = values.iter().map(|&v| v as i64).collect();
+            Tensor::from_shape(&[1, num_tokens], &values).map_err(onnx_error)
+        };
+        let inputs = self.inputs
+            .iter()
+            .map(|input| {
+                let tensor = match input {
+                    ModelInput::InputIds => to_tensor(encoding.get_ids()),
+                    ModelInput::AttentionMask =>
+                        to_tensor(encoding.get_attention_mask()),
+                    ModelInput::TokenTypeIds =>
+                        to_tensor(encoding.get_type_ids()),
+                }?;
+                Ok(tensor.into_tvalue())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let outputs = self.model
+            .run(inputs.into_iter().collect())
+            .map_err(onnx_error)?;
+        let hidden_states = outputs
+            .first()
+            .ok_or_else(|| Error::InvalidData("no model output".to_string()))?
+            .to_array_view::<f32>()
+            .map_err(onnx_error)?;
+        let shape = hidden_states.shape();
+        if shape.len() != 3 || shape[0] != 1 || shape[1] != num_tokens {
+            return Err(Error::InvalidData(format!(
+                "unexpected shape of hidden states: {:?}",
+                shape,
+            )));
+        }
+        Ok(mean_pool(
+            hidden_states.as_slice().ok_or_else(|| {
+                Error::InvalidData("non-contiguous hidden states".to_string())
+            })?,
+            encoding.get_attention_mask(),
+            shape[2],
+        ))
+    }
+}
+
+impl EmbeddingProvider for OnnxEmbeddingProvider {
+    fn embed<'a>(
+        &'a self,
+        inputs: &'a [String],
+    ) -> LocalBoxFuture<'a, Result<Vec<Vec<f32>>, Error>> {
+        let vectors = inputs
+            .iter()
+            .map(|input| self.embed_text(input))
+            .collect();
+        Box::pin(async move { vectors })
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+}
+
+// Averages the hidden states of tokens that are not masked, and normalizes
+// the mean to unit length.
+//
+// `hidden_states` is a row-major matrix of tokens × `hidden_size`.
+fn mean_pool(
+    hidden_states: &[f32],
+    attention_mask: &[u32],
+    hidden_size: usize,
+) -> Vec<f32> {
+    let mut mean = vec![0.0f32; hidden_size];
+    let mut count = 0usize;
+    for (state, mask) in hidden_states.chunks(hidden_size).zip(attention_mask) {
+        if *mask == 0 {
+            continue;
+        }
+        mean.iter_mut().zip(state).for_each(|(m, x)| *m += x);
+        count += 1;
+    }
+    let norm = mean.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if count > 0 && norm > 0.0 {
+        mean.iter_mut().for_each(|m| *m /= norm);
+    }
+    mean
+}
+
+// Returns the first 8 hex digits of the SHA-256 digest of the model files
+// and the maximum number of tokens.
+fn fingerprint(model_dir: &Path, max_tokens: usize) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    for name in ["model.onnx", "tokenizer.json"] {
+        let mut file = File::open(model_dir.join(name))?;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+    }
+    hasher.update(max_tokens.to_le_bytes());
+    Ok(hasher.finalize()[..4].iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn onnx_error(e: impl std::fmt::Display) -> Error {
+    Error::InvalidData(format!("ONNX model error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mean_pool_averages_unmasked_tokens() {
+        let hidden_states = [
+            3.0, 0.0,
+            1.0, 4.0,
+            9.0, 9.0, // padding
+        ];
+        let mean = mean_pool(&hidden_states, &[1, 1, 0], 2);
+        // (2, 2) normalized
+        let x = 1.0 / 2.0f32.sqrt();
+        assert!(mean.iter().all(|m| (m - x).abs() < 1e-6));
+    }
+
+    #[test]
+    fn fingerprint_tells_model_files_and_truncations() {
+        let dir = std::env::temp_dir()
+            .join(format!("mumble-embedding-onnx-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("model.onnx"), "model").unwrap();
+        std::fs::write(dir.join("tokenizer.json"), "{}").unwrap();
+        let original = fingerprint(&dir, 128).unwrap();
+        assert_eq!(original.len(), 8);
+        assert_eq!(fingerprint(&dir, 128).unwrap(), original);
+        assert_ne!(fingerprint(&dir, 256).unwrap(), original);
+        // replaced in place
+        std::fs::write(dir.join("model.onnx"), "model v2").unwrap();
+        assert_ne!(fingerprint(&dir, 128).unwrap(), original);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,232 @@
 //! Deals with the OpenAI API.
 
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use core::str::FromStr;
+use futures::future::LocalBoxFuture;
+use reqwest::{RequestBuilder, StatusCode};
+use reqwest::header::{AUTHORIZATION, HeaderMap, RETRY_AFTER};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::time::Duration;
 
 use crate::error::Error;
+use crate::providers::EmbeddingProvider;
 
-/// Endpoint for embedding.
-pub const EMBEDDING_ENDPOINT: &str = "https://api.openai.com/v1/embeddings";
+/// Default base URL of the OpenAI API.
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Default timeout of a request.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default embedding model.
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/// Number of dimensions of embeddings of [`DEFAULT_EMBEDDING_MODEL`].
+pub const DEFAULT_EMBEDDING_DIMENSIONS: usize = 1536;
+
+/// Returns the number of dimensions of embeddings of a known OpenAI model.
+pub fn known_dimensions(model: &str) -> Option<usize> {
+    match model {
+        "text-embedding-ada-002" | "text-embedding-3-small" => Some(1536),
+        "text-embedding-3-large" => Some(3072),
+        _ => None,
+    }
+}
+
+/// Maximum number of input tokens of the embedding model.
+pub const MAX_INPUT_TOKENS: usize = 8191;
+
+/// Scheme of authentication of API requests.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthScheme {
+    /// `Authorization: Bearer {api_key}` header; e.g., OpenAI.
+    #[default]
+    Bearer,
+    /// `api-key: {api_key}` header; e.g., Azure OpenAI.
+    ApiKey,
+    /// No authentication; e.g., a self-hosted server or local mock.
+    None,
+}
+
+impl FromStr for AuthScheme {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bearer" => Ok(AuthScheme::Bearer),
+            "api-key" => Ok(AuthScheme::ApiKey),
+            "none" => Ok(AuthScheme::None),
+            _ => Err(Error::InvalidData(format!("unknown auth scheme: {}", s))),
+        }
+    }
+}
+
+/// Configuration of the client of the OpenAI API or a compatible one.
+///
+/// For Azure OpenAI, the base URL is that of a deployment; e.g.,
+/// "https://{resource}.openai.azure.com/openai/deployments/{deployment}",
+/// the auth scheme is "api-key", and the API version is required. The
+/// deployment decides the model, so `model` must tell the model of the
+/// deployment; otherwise, embeddings are recorded as those of
+/// [`DEFAULT_EMBEDDING_MODEL`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ClientConfig {
+    /// Base URL that the path of an endpoint follows; e.g., "/embeddings".
+    pub base_url: String,
+    /// Scheme of authentication.
+    pub auth_scheme: AuthScheme,
+    /// `api-version` query parameter; e.g., "2024-02-01" for Azure OpenAI.
+    pub api_version: Option<String>,
+    /// `OpenAI-Organization` header.
+    pub organization: Option<String>,
+    /// `OpenAI-Project` header.
+    pub project: Option<String>,
+    /// Timeout of a request; in seconds in JSON.
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub timeout: Duration,
+    /// Embedding model.
+    ///
+    /// [`DEFAULT_EMBEDDING_MODEL`] if omitted.
+    pub model: Option<String>,
+    /// Number of dimensions of embeddings of `model`.
+    ///
+    /// Required unless `model` is a known OpenAI model; see
+    /// [`known_dimensions`].
+    pub dimensions: Option<usize>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            auth_scheme: AuthScheme::default(),
+            api_version: None,
+            organization: None,
+            project: None,
+            timeout: DEFAULT_TIMEOUT,
+            model: None,
+            dimensions: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Loads the configuration from environment variables.
+    ///
+    /// - `OPENAI_BASE_URL`: base URL.
+    /// - `OPENAI_AUTH_SCHEME`: "bearer", "api-key", or "none".
+    /// - `OPENAI_API_VERSION`: `api-version` query parameter.
+    /// - `OPENAI_ORGANIZATION`: `OpenAI-Organization` header.
+    /// - `OPENAI_PROJECT`: `OpenAI-Project` header.
+    /// - `OPENAI_TIMEOUT`: timeout of a request in seconds.
+    /// - `OPENAI_EMBEDDING_MODEL`: embedding model.
+    /// - `OPENAI_EMBEDDING_DIMENSIONS`: number of dimensions of embeddings.
+    ///
+    /// Defaults apply to missing variables.
+    pub fn from_env() -> Result<Self, Error> {
+        let var = |name: &str| env::var(name).ok();
+        let mut config = Self::default();
+        if let Some(base_url) = var("OPENAI_BASE_URL") {
+            config.base_url = base_url;
+        }
+        if let Some(auth_scheme) = var("OPENAI_AUTH_SCHEME") {
+            config.auth_scheme = auth_scheme.parse()?;
+        }
+        config.api_version = var("OPENAI_API_VERSION");
+        config.organization = var("OPENAI_ORGANIZATION");
+        config.project = var("OPENAI_PROJECT");
+        if let Some(timeout) = var("OPENAI_TIMEOUT") {
+            config.timeout = timeout.parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| Error::InvalidData(format!(
+                    "invalid OPENAI_TIMEOUT: {}",
+                    timeout,
+                )))?;
+        }
+        config.model = var("OPENAI_EMBEDDING_MODEL");
+        if let Some(dimensions) = var("OPENAI_EMBEDDING_DIMENSIONS") {
+            config.dimensions = Some(dimensions.parse().map_err(|_| {
+                Error::InvalidData(format!(
+                    "invalid OPENAI_EMBEDDING_DIMENSIONS: {}",
+                    dimensions,
+                ))
+            })?);
+        }
+        Ok(config)
+    }
+
+    /// Loads the configuration from a JSON file.
+    ///
+    /// Defaults apply to missing fields.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    /// Returns the URL of an endpoint at a given path; e.g., "/embeddings".
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.base_url.trim_end_matches('/'), path)
+    }
+
+    /// Returns the embedding model and the number of dimensions of its
+    /// embeddings.
+    ///
+    /// Fails if the dimensions of `model` are neither given nor known.
+    pub fn embedding_model(&self) -> Result<(String, usize), Error> {
+        let model = self.model.as_deref().unwrap_or(DEFAULT_EMBEDDING_MODEL);
+        let dimensions = self.dimensions
+            .or_else(|| known_dimensions(model))
+            .ok_or_else(|| Error::InvalidData(format!(
+                "no dimensions given for embedding model: {}",
+                model,
+            )))?;
+        Ok((model.to_string(), dimensions))
+    }
+
+    /// Returns if requests need an API key.
+    pub fn needs_api_key(&self) -> bool {
+        self.auth_scheme != AuthScheme::None
+    }
+
+    // Builds a client.
+    fn build_client(&self) -> Result<reqwest::Client, Error> {
+        Ok(reqwest::Client::builder().timeout(self.timeout).build()?)
+    }
+
+    // Adds the authentication, headers, and query parameters to a request.
+    fn apply(&self, request: RequestBuilder, api_key: &str) -> RequestBuilder {
+        let mut request = match self.auth_scheme {
+            AuthScheme::Bearer =>
+                request.header(AUTHORIZATION, format!("Bearer {}", api_key)),
+            AuthScheme::ApiKey => request.header("api-key", api_key),
+            AuthScheme::None => request,
+        };
+        if let Some(api_version) = self.api_version.as_ref() {
+            request = request.query(&[("api-version", api_version)]);
+        }
+        if let Some(organization) = self.organization.as_ref() {
+            request = request.header("OpenAI-Organization", organization);
+        }
+        if let Some(project) = self.project.as_ref() {
+            request = request.header("OpenAI-Project", project);
+        }
+        request
+    }
+}
+
+fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+}
 
 /// Request body for embedding.
 #[derive(Clone, Debug, Serialize)]
@@ -45,6 +266,80 @@ pub struct EmbeddingData {
     pub index: usize,
 }
 
+/// Error returned by the OpenAI API.
+///
+/// Deserialized from the `error` object of an error response body.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct ApiError {
+    /// Type; e.g., "invalid_request_error" and "insufficient_quota".
+    #[serde(rename = "type", default)]
+    pub type_: Option<String>,
+    /// Code; e.g., "context_length_exceeded" and "invalid_api_key".
+    #[serde(default, deserialize_with = "deserialize_code")]
+    pub code: Option<String>,
+    /// Message.
+    #[serde(default)]
+    pub message: String,
+    /// Parameter that caused the error.
+    #[serde(default)]
+    pub param: Option<String>,
+}
+
+impl ApiError {
+    /// Parses an error response body.
+    ///
+    /// `None` if the body is not an error of the OpenAI API.
+    pub fn from_body(body: &str) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: ApiError,
+        }
+        serde_json::from_str::<ErrorBody>(body).ok().map(|body| body.error)
+    }
+
+    /// Returns if an input exceeded the context length of the model.
+    pub fn is_context_length_exceeded(&self) -> bool {
+        self.code.as_deref() == Some("context_length_exceeded")
+    }
+
+    /// Returns if the quota has run out.
+    pub fn is_insufficient_quota(&self) -> bool {
+        self.code.as_deref() == Some("insufficient_quota")
+            || self.type_.as_deref() == Some("insufficient_quota")
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(kind) = self.code.as_deref().or(self.type_.as_deref()) {
+            write!(f, "{}: ", kind)?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(param) = self.param.as_ref() {
+            write!(f, " (param: {})", param)?;
+        }
+        Ok(())
+    }
+}
+
+// Deserializes a code that may be a string or number; e.g., Azure OpenAI
+// returns "429" whereas some compatible servers return 429.
+fn deserialize_code<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Code {
+        String(String),
+        Number(i64),
+    }
+    Ok(Option::<Code>::deserialize(deserializer)?.map(|code| match code {
+        Code::String(s) => s,
+        Code::Number(n) => n.to_string(),
+    }))
+}
+
 /// API usage.
 #[derive(Clone, Debug, Deserialize)]
 pub struct Usage {
@@ -52,21 +347,574 @@ pub struct Usage {
     pub total_tokens: u64,
 }
 
+/// Policy on retrying failed requests.
+///
+/// Only a rate-limited request (429), a server error (5xx), and a reset
+/// connection are retried.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries.
+    pub max_retries: usize,
+    /// Delay before the first retry, which doubles at every retry.
+    pub initial_delay: Duration,
+    /// Maximum delay of a retry unless the server asks for a longer one.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns a policy that never retries.
+    pub fn never() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the delay before the `retry`-th retry (1-based) without any
+    /// hint from the server.
+    ///
+    /// Exponential backoff with jitter; a random delay between the half and
+    /// whole of `initial_delay * 2^(retry - 1)` capped at `max_delay`.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31) as u32;
+        let delay = self.initial_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        delay / 2 + delay.mul_f64(random_fraction() / 2.0)
+    }
+}
+
 /// Creates an embedding vector of given texts.
 ///
-/// Uses `reqwest` to send a POST request to the OpenAI API.
+/// Uses `reqwest` to send a POST request to the "/embeddings" endpoint of
+/// the API configured by `config`. Retries a failed request according to
+/// `retry_policy`, and logs every attempt.
+///
+/// A retry waits as long as `Retry-After` header of the failed response
+/// asks. Otherwise, it waits until the exhausted limit in `x-ratelimit-*`
+/// headers resets, or backs off exponentially.
 pub async fn create_embeddings(
+    config: &ClientConfig,
     request: &EmbeddingRequestBody,
-    api_key: String,
+    api_key: &str,
+    retry_policy: &RetryPolicy,
 ) -> Result<EmbeddingResponseBody, Error> {
-    let res = reqwest::Client::new()
-        .post(EMBEDDING_ENDPOINT)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(request)
-        .send().await?;
-    if !res.status().is_success() {
-        return Err(Error::HttpError(res.status()));
-    }
-    let res = res.json::<EmbeddingResponseBody>().await?;
-    Ok(res)
+    let client = config.build_client()?;
+    let endpoint = config.endpoint("/embeddings");
+    let mut retries = 0;
+    loop {
+        println!(
+            "requesting embeddings of {} input(s) (attempt {}/{})",
+            request.input.len(),
+            retries + 1,
+            retry_policy.max_retries + 1,
+        );
+        let res = config.apply(client.post(&endpoint), api_key)
+            .json(request)
+            .send().await;
+        let (error, hint) = match res {
+            Ok(res) if res.status().is_success() => {
+                let res = res.json::<EmbeddingResponseBody>().await?;
+                return Ok(res);
+            },
+            Ok(res) => {
+                let status = res.status();
+                let hint = retry_delay_hint(res.headers());
+                let body = res.text().await.unwrap_or_default();
+                let error = match ApiError::from_body(&body) {
+                    Some(e) => Error::OpenAiError(status, e),
+                    None => Error::HttpError(status),
+                };
+                // the quota does not recover by retrying
+                if !is_retryable_status(status) || error.is_insufficient_quota()
+                {
+                    println!("request failed: {}", error);
+                    return Err(error);
+                }
+                (error, hint)
+            },
+            Err(e) => {
+                if !is_connection_reset(&e) {
+                    println!("request failed: {}", e);
+                    return Err(e.into());
+                }
+                (e.into(), None)
+            },
+        };
+        if retries >= retry_policy.max_retries {
+            println!("request failed: {}, giving up", error);
+            return Err(error);
+        }
+        retries += 1;
+        let delay = hint.unwrap_or_else(|| retry_policy.backoff(retries));
+        println!("request failed: {}, retrying in {:?}", error, delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Returns if a request that failed with a given status may be retried.
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Returns if a request failed because the connection was reset.
+fn is_connection_reset(e: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe,
+            );
+        }
+        source = e.source();
+    }
+    false
+}
+
+// Returns the delay that the headers of a failed response ask for.
+//
+// Prefers `Retry-After` in seconds or as an HTTP-date. Otherwise, takes the
+// longest reset time of the exhausted limits in `x-ratelimit-*` headers.
+fn retry_delay_hint(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)?.to_str().ok();
+    if let Some(delay) = header(RETRY_AFTER.as_str())
+        .and_then(|v| parse_retry_after(v, Utc::now()))
+    {
+        return Some(delay);
+    }
+    ["requests", "tokens"]
+        .into_iter()
+        .filter(|limit| {
+            header(&format!("x-ratelimit-remaining-{}", limit))
+                .is_some_and(|v| v.trim() == "0")
+        })
+        .filter_map(|limit| {
+            let reset = header(&format!("x-ratelimit-reset-{}", limit))?;
+            parse_reset_duration(reset)
+        })
+        .max()
+}
+
+// Parses `Retry-After` in seconds or as an HTTP-date; e.g., "120" and
+// "Wed, 21 Oct 2015 07:28:00 GMT".
+//
+// A date before `now` means no delay.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+// Parses a duration in `x-ratelimit-reset-*` headers; e.g., "1s", "6m0s",
+// "20ms", and "1h2m3.5s".
+fn parse_reset_duration(s: &str) -> Option<Duration> {
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|ch: char| !ch.is_ascii_digit() && ch != '.')
+            .unwrap_or(rest.len());
+        let value: f64 = rest[..end].parse().ok()?;
+        rest = &rest[end..];
+        let unit_end = rest
+            .find(|ch: char| ch.is_ascii_digit() || ch == '.')
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_end] {
+            "h" => value * 3600.0,
+            "m" => value * 60.0,
+            "s" => value,
+            "ms" => value / 1000.0,
+            _ => return None,
+        };
+        rest = &rest[unit_end..];
+        total += Duration::try_from_secs_f64(seconds).ok()?;
+    }
+    Some(total)
+}
+
+// Returns a random number in [0, 1).
+//
+// Good enough for jitter without another dependency.
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Provider of embeddings through the OpenAI API.
+#[derive(Clone, Debug)]
+pub struct OpenAiEmbeddingProvider {
+    config: ClientConfig,
+    api_key: String,
+    model: String,
+    dimensions: usize,
+    user: Option<String>,
+    retry_policy: RetryPolicy,
+}
+
+impl OpenAiEmbeddingProvider {
+    /// Creates a provider of [`DEFAULT_EMBEDDING_MODEL`].
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            config: ClientConfig::default(),
+            api_key: api_key.into(),
+            model: DEFAULT_EMBEDDING_MODEL.to_string(),
+            dimensions: DEFAULT_EMBEDDING_DIMENSIONS,
+            user: Some("mumble_embedding".to_string()),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Creates a provider with the client configuration in environment
+    /// variables; see [`ClientConfig::from_env`].
+    ///
+    /// See [`OpenAiEmbeddingProvider::from_env_with_config`] for the API key.
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_env_with_config(ClientConfig::from_env()?)
+    }
+
+    /// Creates a provider with a given client configuration.
+    ///
+    /// Takes the API key from the `OPENAI_API_KEY` environment variable,
+    /// which may be missing only if the configuration needs no API key.
+    pub fn from_env_with_config(config: ClientConfig) -> Result<Self, Error> {
+        let api_key = match env::var("OPENAI_API_KEY") {
+            Ok(api_key) => api_key,
+            Err(_) if !config.needs_api_key() => String::new(),
+            Err(_) => return Err(Error::InvalidContext(
+                "no OPENAI_API_KEY set".to_string(),
+            )),
+        };
+        Self::new(api_key).with_client_config(config)
+    }
+
+    /// Sets the configuration of the client.
+    ///
+    /// Also uses the embedding model of the configuration. Fails if the
+    /// dimensions of the model are unknown.
+    pub fn with_client_config(
+        self,
+        config: ClientConfig,
+    ) -> Result<Self, Error> {
+        let (model, dimensions) = config.embedding_model()?;
+        let mut provider = self.with_model(model, dimensions);
+        provider.config = config;
+        Ok(provider)
+    }
+
+    /// Uses a given model that makes embeddings of given dimensions.
+    pub fn with_model(
+        mut self,
+        model: impl Into<String>,
+        dimensions: usize,
+    ) -> Self {
+        self.model = model.into();
+        self.dimensions = dimensions;
+        self
+    }
+
+    /// Sets the policy on retrying failed requests.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the end-user ID sent with requests.
+    pub fn with_user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+}
+
+impl EmbeddingProvider for OpenAiEmbeddingProvider {
+    fn max_input_tokens(&self) -> Option<usize> {
+        Some(MAX_INPUT_TOKENS)
+    }
+
+    fn embed<'a>(
+        &'a self,
+        inputs: &'a [String],
+    ) -> LocalBoxFuture<'a, Result<Vec<Vec<f32>>, Error>> {
+        Box::pin(async move {
+            let request = EmbeddingRequestBody {
+                model: self.model.clone(),
+                input: inputs.to_vec(),
+                user: self.user.clone(),
+            };
+            let res = create_embeddings(
+                &self.config,
+                &request,
+                &self.api_key,
+                &self.retry_policy,
+            ).await?;
+            println!("usage: {:?}", res.usage);
+            let mut data = res.data;
+            if data.len() != inputs.len() {
+                return Err(Error::InvalidData(format!(
+                    "{} embeddings returned for {} inputs",
+                    data.len(),
+                    inputs.len(),
+                )));
+            }
+            data.sort_by_key(|d| d.index);
+            Ok(data
+                .into_iter()
+                .map(|d| d.embedding.into_iter().map(|x| x as f32).collect())
+                .collect())
+        })
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn client_config_loads_azure_settings() {
+        let config: ClientConfig = serde_json::from_str(r#"{
+            "baseUrl": "https://example.com/openai/deployments/ada/",
+            "authScheme": "api-key",
+            "apiVersion": "2024-02-01",
+            "timeout": 1.5
+        }"#).unwrap();
+        assert_eq!(
+            config.endpoint("/embeddings"),
+            "https://example.com/openai/deployments/ada/embeddings",
+        );
+        assert_eq!(config.auth_scheme, AuthScheme::ApiKey);
+        assert_eq!(config.timeout, Duration::from_millis(1500));
+        assert_eq!(config.organization, None);
+        assert!(config.embedding_model().is_ok_and(|(model, dimensions)| {
+            model == DEFAULT_EMBEDDING_MODEL && dimensions == 1536
+        }));
+        let config: ClientConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, ClientConfig::default());
+
+        // the deployment decides the model
+        let config: ClientConfig = serde_json::from_str(r#"{
+            "model": "text-embedding-3-large"
+        }"#).unwrap();
+        let provider = OpenAiEmbeddingProvider::new("secret")
+            .with_client_config(config)
+            .unwrap();
+        assert_eq!(provider.model_id(), "text-embedding-3-large");
+        assert_eq!(provider.dimensions(), 3072);
+        let config: ClientConfig = serde_json::from_str(r#"{
+            "model": "self-hosted-model"
+        }"#).unwrap();
+        assert!(config.embedding_model().is_err());
+    }
+
+    #[tokio::test]
+    async fn create_embeddings_retries_with_configured_client() {
+        use crate::stub_server::{StubServer, http_response};
+
+        // fails the first request as rate limited
+        let server = StubServer::bind().await;
+        let host = server.host().to_string();
+        let mut is_first = true;
+        let requests = server.serve(move |_| {
+            if is_first {
+                is_first = false;
+                let body = serde_json::json!({
+                    "error": {
+                        "message": "Slow down.",
+                        "type": "requests",
+                        "code": "rate_limit_exceeded",
+                    },
+                }).to_string();
+                http_response(
+                    "429 Too Many Requests",
+                    &[("Retry-After", "0")],
+                    &body,
+                )
+            } else {
+                let body = serde_json::json!({
+                    "object": "list",
+                    "data": [
+                        {
+                            "object": "embedding",
+                            "embedding": [0.5],
+                            "index": 0,
+                        },
+                    ],
+                    "model": "ada",
+                    "usage": { "prompt_tokens": 1, "total_tokens": 1 },
+                }).to_string();
+                http_response(
+                    "200 OK",
+                    &[("Content-Type", "application/json")],
+                    &body,
+                )
+            }
+        });
+
+        let config = ClientConfig {
+            base_url: format!("http://{}/openai/deployments/ada", host),
+            auth_scheme: AuthScheme::ApiKey,
+            api_version: Some("2024-02-01".to_string()),
+            project: Some("proj_1".to_string()),
+            ..ClientConfig::default()
+        };
+        let request = EmbeddingRequestBody {
+            model: "ada".to_string(),
+            input: vec!["Hello.".to_string()],
+            user: None,
+        };
+        let res = create_embeddings(
+            &config,
+            &request,
+            "secret",
+            &RetryPolicy::default(),
+        ).await.unwrap();
+        assert_eq!(res.data[0].embedding, vec![0.5]);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let request = requests[1].to_lowercase();
+        assert!(request.starts_with(
+            "post /openai/deployments/ada/embeddings?api-version=2024-02-01 ",
+        ));
+        assert!(request.contains("\r\napi-key: secret\r\n"));
+        assert!(request.contains("\r\nopenai-project: proj_1\r\n"));
+        assert!(!request.contains("authorization"));
+    }
+
+    #[test]
+    fn retry_delay_hint_honors_retry_after_and_rate_limits() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+        assert_eq!(
+            retry_delay_hint(&headers(&[
+                ("retry-after", "2"),
+                ("x-ratelimit-remaining-tokens", "0"),
+                ("x-ratelimit-reset-tokens", "6m0s"),
+            ])),
+            Some(Duration::from_secs(2)),
+        );
+        assert_eq!(
+            retry_delay_hint(&headers(&[
+                ("x-ratelimit-remaining-requests", "0"),
+                ("x-ratelimit-reset-requests", "1.5s"),
+                ("x-ratelimit-remaining-tokens", "0"),
+                ("x-ratelimit-reset-tokens", "20ms"),
+            ])),
+            Some(Duration::from_millis(1500)),
+        );
+        // the limit of tokens is not exhausted
+        assert_eq!(
+            retry_delay_hint(&headers(&[
+                ("x-ratelimit-remaining-tokens", "100"),
+                ("x-ratelimit-reset-tokens", "1m"),
+            ])),
+            None,
+        );
+        // an HTTP-date
+        let now: DateTime<Utc> = "2015-10-21T07:26:00Z".parse().unwrap();
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::from_secs(120)),
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:25:00 GMT", now),
+            Some(Duration::ZERO),
+        );
+        assert_eq!(parse_retry_after("later", now), None);
+        assert!(retry_delay_hint(&headers(&[
+            ("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ("x-ratelimit-remaining-tokens", "0"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+        ])).is_some_and(|delay| delay.is_zero()));
+        assert_eq!(
+            parse_reset_duration("1h2m3.5s"),
+            Some(Duration::from_millis(3_723_500)),
+        );
+        assert_eq!(parse_reset_duration("soon"), None);
+    }
+
+    #[test]
+    fn api_error_parses_openai_error_bodies() {
+        let e = ApiError::from_body(r#"{
+            "error": {
+                "message": "This model's maximum context length is 8191.",
+                "type": "invalid_request_error",
+                "param": null,
+                "code": "context_length_exceeded"
+            }
+        }"#).unwrap();
+        assert!(e.is_context_length_exceeded());
+        assert!(!e.is_insufficient_quota());
+        let error = Error::OpenAiError(StatusCode::BAD_REQUEST, e);
+        assert!(error.is_context_length_exceeded());
+        assert_eq!(
+            error.to_string(),
+            concat!(
+                "OpenAI error (400 Bad Request): context_length_exceeded:",
+                " This model's maximum context length is 8191.",
+            ),
+        );
+
+        let e = ApiError::from_body(r#"{
+            "error": {
+                "message": "You exceeded your current quota.",
+                "type": "insufficient_quota",
+                "param": null,
+                "code": "insufficient_quota"
+            }
+        }"#).unwrap();
+        assert!(e.is_insufficient_quota());
+        let e = ApiError::from_body(
+            r#"{"error": {"code": 429, "message": "Rate limit exceeded."}}"#,
+        ).unwrap();
+        assert_eq!(e.code.as_deref(), Some("429"));
+        assert_eq!(ApiError::from_body("<html>Bad Gateway</html>"), None);
+    }
+
+    #[test]
+    fn retry_policy_backs_off_exponentially_with_jitter() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+        };
+        for (retry, max) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 10), (9, 10)] {
+            let delay = policy.backoff(retry);
+            let max = Duration::from_secs(max);
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::BAD_GATEWAY));
+        assert!(!is_retryable_status(StatusCode::BAD_REQUEST));
+    }
 }
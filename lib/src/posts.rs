@@ -1,17 +1,26 @@
 //! Dealing with posts (mumblings).
 
 use core::ops::Range;
-use futures::stream::{Stream, StreamExt};
-use serde::{Deserialize, Serialize};
+use core::str::FromStr;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::markdown::extract_text_blocks;
+use crate::cache::{EmbeddingCache, normalize_text};
+use crate::markdown::{TextBlock, extract_headings, extract_text_blocks};
 use crate::error::Error;
-use crate::openai::{EmbeddingRequestBody, create_embeddings};
-use crate::s3::ObjectList;
-use crate::text::extract_sentences;
+use crate::providers::{
+    EmbeddingProvider,
+    estimate_tokens,
+    truncate_to_tokens,
+};
+use crate::text::{LineBreakMode, extract_sentences};
 
 /// Post.
-#[derive(Clone, Debug, Deserialize)]
+///
+/// An ActivityPub object; e.g., `Note`, `Article`, and `Tombstone`.
+#[derive(Clone, Debug, Default, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Post {
     /// ID.
@@ -20,62 +29,443 @@ pub struct Post {
     #[serde(rename = "type")]
     pub type_: String,
     /// Contents.
+    ///
+    /// Empty if the object has no content; e.g., `Tombstone`.
+    #[serde(default)]
     pub content: String,
     /// Published.
-    pub published: String,
+    ///
+    /// `None` if the object has no published date; e.g., `Tombstone`.
+    #[serde(default)]
+    pub published: Option<DateTime<Utc>>,
     /// Source.
-    pub source: Option<PostSource>,
+    pub source: Option<PostSourceContent>,
+    /// Name; i.e., title of an `Article`.
+    pub name: Option<String>,
+    /// Summary; e.g., abstract of an `Article` or content warning of a
+    /// `Note`.
+    pub summary: Option<String>,
+    /// ID of the object that this post replies to.
+    pub in_reply_to: Option<String>,
+    /// Primary audience.
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    pub to: Vec<String>,
+    /// Secondary audience.
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    pub cc: Vec<String>,
+    /// Tags; e.g., hashtags and mentions.
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    pub tag: Vec<PostTag>,
+    /// Attachments; e.g., images.
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    pub attachment: Vec<PostAttachment>,
+    /// Updated.
+    pub updated: Option<String>,
+    /// Contents in languages keyed by language tags.
+    #[serde(default)]
+    pub content_map: HashMap<String, String>,
+    /// Object where the post was loaded from.
+    #[serde(skip)]
+    pub origin: Option<PostOrigin>,
+    /// Platform where the post was imported from; e.g., "mastodon".
+    ///
+    /// `None` for a post on Mumble.
+    #[serde(skip)]
+    pub source_platform: Option<String>,
+}
+
+impl Post {
+    /// Returns the text to be processed.
+    ///
+    /// Prefers the source content to the rendered content.
+    pub fn text(&self) -> &str {
+        if let Some(source) = self.source.as_ref() {
+            &source.content
+        } else {
+            &self.content
+        }
+    }
+
+    /// Returns if the object type is supported.
+    ///
+    /// `Note`, `Article`, and `Page` are supported.
+    pub fn is_supported(&self) -> bool {
+        matches!(self.type_.as_str(), "Note" | "Article" | "Page")
+    }
+
+    /// Returns if the name of the object is embedded.
+    ///
+    /// Only the name of an `Article` or `Page` is embedded, because the name
+    /// of other types is usually absent or a part of the content.
+    pub fn has_title(&self) -> bool {
+        matches!(self.type_.as_str(), "Article" | "Page")
+            && self.name.as_ref().is_some_and(|name| !name.is_empty())
+    }
+
+    /// Returns if the post has a content warning.
+    ///
+    /// The summary of an object other than an `Article` or `Page` is a
+    /// content warning.
+    pub fn has_content_warning(&self) -> bool {
+        is_content_warning(&self.type_, self.summary.as_deref())
+    }
+
+    /// Returns if the post is addressed to the public.
+    ///
+    /// A post is public if `to` or `cc` has the public collection; i.e.,
+    /// public and unlisted posts on Mastodon.
+    pub fn is_public(&self) -> bool {
+        self.to.iter().chain(self.cc.iter()).any(|a| is_public_audience(a))
+    }
+
+    /// Returns if the post is a reply.
+    pub fn is_reply(&self) -> bool {
+        self.in_reply_to.is_some()
+    }
+
+    /// Returns the hash of the content of the post.
+    ///
+    /// The hash covers the type, name, summary, text, and alt texts of
+    /// attachments, which affect embeddings, and the published time and
+    /// parent, which are saved with embeddings, as a hex-encoded SHA-256
+    /// digest.
+    pub fn content_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        let published = self.published.map(|published| published.to_rfc3339());
+        let parts = [
+            Some(self.type_.as_str()),
+            self.name.as_deref(),
+            self.summary.as_deref(),
+            Some(self.text()),
+            published.as_deref(),
+            self.in_reply_to.as_deref(),
+        ];
+        let alt_texts = self.attachment.iter().map(|a| a.name.as_deref());
+        for part in parts.into_iter().chain(alt_texts) {
+            // distinguishes a missing part from an empty one
+            match part {
+                Some(part) => {
+                    hasher.update([1u8]);
+                    hasher.update(part.len().to_le_bytes());
+                    hasher.update(part.as_bytes());
+                },
+                None => hasher.update([0u8]),
+            }
+        }
+        to_hex(&hasher.finalize())
+    }
+
+    /// Returns the language of the post.
+    ///
+    /// The language tag of the content map if it has exactly one entry.
+    pub fn language(&self) -> Option<&str> {
+        match self.content_map.keys().collect::<Vec<_>>()[..] {
+            [language] => Some(language),
+            _ => None,
+        }
+    }
+
+    /// Returns the metadata of the post.
+    pub fn metadata(&self) -> PostMetadata {
+        PostMetadata {
+            type_: self.type_.clone(),
+            name: self.name.clone(),
+            summary: self.summary.clone(),
+            in_reply_to: self.in_reply_to.clone(),
+            tag: self.tag.clone(),
+            attachment: self.attachment.clone(),
+            updated: self.updated.clone(),
+            source_platform: self.source_platform.clone(),
+            thread_root: None,
+            published: self.published,
+            language: self.language().map(str::to_string),
+        }
+    }
 }
 
-/// Post source.
+/// Source content of a post; e.g., Markdown.
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct PostSource {
+pub struct PostSourceContent {
     /// Content.
     pub content: String,
     /// MIME type
     pub media_type: String,
 }
 
-/// Lists all posts of a specified user.
-pub async fn list_posts(
-    bucket_name: &str,
-    username: &str,
-) -> impl Stream<Item = Result<Post, Error>> {
-    let config = aws_config::load_from_env().await;
-    let client = aws_sdk_s3::Client::new(&config);
-    let bucket_name = bucket_name.to_string();
-    ObjectList::new(
-        &bucket_name,
-        format!("objects/users/{}/posts/", username),
-        client,
-    )
-        .into_stream()
-        .then(move |o| load_post(
-            bucket_name.clone(),
-            o,
-            config.clone(),
-        ))
-}
-
-async fn load_post(
-    bucket_name: String,
-    object: aws_sdk_s3::types::Object,
-    config: aws_config::SdkConfig,
-) -> Result<Post, Error> {
-    let client = aws_sdk_s3::Client::new(&config);
-    let key = object.key.ok_or(Error::InvalidData(format!("missing key")))?;
-    println!("retrieving object: {}", key);
-    let result = client.get_object()
-        .bucket(bucket_name)
-        .key(key)
-        .send().await?;
-    let body = result.body.collect().await?;
-    let post = serde_json::from_slice::<Post>(&body.into_bytes())?;
-    Ok(post)
+/// Object where a post was loaded from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PostOrigin {
+    /// Key of the object.
+    pub key: String,
+    /// ETag of the object.
+    pub e_tag: Option<String>,
+}
+
+/// Tag of a post.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostTag {
+    /// Type; e.g., `Hashtag` and `Mention`.
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Name; e.g., "#hashtag" and "@username@domain".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub href: Option<String>,
+}
+
+/// Attachment of a post.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostAttachment {
+    /// Type; e.g., `Document` and `Image`.
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// MIME type.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    /// URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Name; i.e., alt text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Metadata of a post carried to embeddings.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostMetadata {
+    /// Object type.
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Summary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    /// ID of the object that the post replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    /// Tags.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tag: Vec<PostTag>,
+    /// Attachments.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachment: Vec<PostAttachment>,
+    /// Updated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+    /// Platform where the post was imported from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_platform: Option<String>,
+    /// ID of the root of the thread where the post is; see
+    /// [`crate::threads::ThreadIndex::thread_root`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_root: Option<String>,
+    /// Published.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<DateTime<Utc>>,
+    /// Language tag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+impl PostMetadata {
+    /// Returns if the post has a content warning; see
+    /// [`Post::has_content_warning`].
+    pub fn has_content_warning(&self) -> bool {
+        is_content_warning(&self.type_, self.summary.as_deref())
+    }
+}
+
+// Returns if the summary of an object is a content warning.
+fn is_content_warning(type_: &str, summary: Option<&str>) -> bool {
+    !matches!(type_, "Article" | "Page")
+        && summary.is_some_and(|summary| !summary.trim().is_empty())
+}
+
+// Encodes bytes in lowercase hex.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Deserializes a property that may be either a single value or an array.
+//
+// ActivityPub allows a single value in place of an array of one value.
+fn deserialize_one_or_many<'de, D, T>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match Option::<OneOrMany<T>>::deserialize(deserializer)? {
+        Some(OneOrMany::One(value)) => vec![value],
+        Some(OneOrMany::Many(values)) => values,
+        None => Vec::new(),
+    })
+}
+
+/// Public collection in ActivityPub.
+pub const PUBLIC_AUDIENCE: &str =
+    "https://www.w3.org/ns/activitystreams#Public";
+
+// Returns if a given audience is the public collection.
+//
+// Accepts the compact forms as well.
+fn is_public_audience(audience: &str) -> bool {
+    matches!(audience, PUBLIC_AUDIENCE | "as:Public" | "Public")
+}
+
+/// Filter of posts.
+///
+/// A post passes the filter if all of the following hold:
+/// - it is published in `[since, until)`; a post without a published date
+///   passes only if neither is given
+/// - it is addressed to the public unless `public_only` is `false`
+/// - it is a reply or top-level post as `replies` tells
+#[derive(Clone, Debug)]
+pub struct PostFilter {
+    /// Earliest published date.
+    pub since: Option<DateTime<Utc>>,
+    /// Published date that a post must be earlier than.
+    pub until: Option<DateTime<Utc>>,
+    /// Whether to accept only public posts.
+    pub public_only: bool,
+    /// How replies are treated.
+    pub replies: ReplyFilter,
+}
+
+impl Default for PostFilter {
+    fn default() -> Self {
+        Self {
+            since: None,
+            until: None,
+            public_only: true,
+            replies: ReplyFilter::Include,
+        }
+    }
+}
+
+impl PostFilter {
+    /// Returns if a given post passes the filter.
+    pub fn matches(&self, post: &Post) -> bool {
+        if self.since.is_some() || self.until.is_some() {
+            let Some(published) = post.published else {
+                return false;
+            };
+            if self.since.is_some_and(|since| published < since) {
+                return false;
+            }
+            if self.until.is_some_and(|until| published >= until) {
+                return false;
+            }
+        }
+        if self.public_only && !post.is_public() {
+            return false;
+        }
+        match self.replies {
+            ReplyFilter::Include => true,
+            ReplyFilter::Exclude => !post.is_reply(),
+            ReplyFilter::Only => post.is_reply(),
+        }
+    }
+}
+
+/// How replies are treated by a [`PostFilter`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ReplyFilter {
+    /// Accepts both replies and top-level posts.
+    #[default]
+    Include,
+    /// Accepts only top-level posts.
+    Exclude,
+    /// Accepts only replies.
+    Only,
+}
+
+impl FromStr for ReplyFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "include" => Ok(ReplyFilter::Include),
+            "exclude" => Ok(ReplyFilter::Exclude),
+            "only" => Ok(ReplyFilter::Only),
+            _ => Err(Error::InvalidData(
+                format!("unknown reply filter: {}", s),
+            )),
+        }
+    }
+}
+
+/// Granularity of an embedded unit.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    Hash,
+    PartialEq,
+    Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// Whole post.
+    Post,
+    /// Text block; e.g., paragraph, list item, and code block.
+    Block,
+    /// Sentence.
+    #[default]
+    Sentence,
+}
+
+impl Granularity {
+    /// Returns the string representation.
+    ///
+    /// Used as the value of the "granularity" attribute in a database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Post => "post",
+            Self::Block => "block",
+            Self::Sentence => "sentence",
+        }
+    }
+}
+
+impl FromStr for Granularity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "post" => Ok(Self::Post),
+            "block" => Ok(Self::Block),
+            "sentence" => Ok(Self::Sentence),
+            _ => Err(Error::InvalidData(format!(
+                "granularity must be one of post, block, or sentence \
+                 but got {}",
+                s,
+            ))),
+        }
+    }
 }
 
 /// Sentence in a post.
+///
+/// Also represents a text block or a whole post depending on `granularity`.
 #[derive(Clone, Debug)]
 pub struct PostSentence {
     /// ID of the source post.
@@ -84,72 +474,1503 @@ pub struct PostSentence {
     pub content: String,
     /// Range in the post.
     pub range: Range<usize>,
+    /// Granularity.
+    pub granularity: Granularity,
+    /// Part of the post.
+    pub part: PostPart,
+    /// Index among the preceding units of the same granularity and content
+    /// in the post.
+    pub occurrence: usize,
+    /// Optional context to be included in the embedding input.
+    pub context: Option<SentenceContext>,
+    /// Metadata of the source post.
+    pub metadata: PostMetadata,
+}
+
+/// Scheme of unit IDs.
+///
+/// IDs of a whole post, name, and summary are common to all the schemes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IdScheme {
+    /// Range in the post; see [`PostSentence::id`].
+    ///
+    /// Any edit above a unit changes the ID.
+    #[default]
+    Range,
+    /// Hash of the normalized content and occurrence index; see
+    /// [`make_content_id`].
+    ///
+    /// Survives edits of other units.
+    Content,
+}
+
+impl FromStr for IdScheme {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "range" => Ok(IdScheme::Range),
+            "content" => Ok(IdScheme::Content),
+            _ => Err(Error::InvalidData(format!("unknown ID scheme: {}", s))),
+        }
+    }
+}
+
+/// Makes a content-hash based ID of a unit.
+///
+/// - sentence: `{post_id}#s-{hash}-{occurrence}`
+/// - text block: `{post_id}#b-{hash}-{occurrence}`
+/// - whole post: `{post_id}`
+///
+/// `hash` is the first 16 hex digits of the SHA-256 digest of the content
+/// whose whitespaces are collapsed. `occurrence` is the index among units
+/// of the same granularity and content in the post.
+pub fn make_content_id(
+    post_id: &str,
+    granularity: Granularity,
+    content: &str,
+    occurrence: usize,
+) -> String {
+    let prefix = match granularity {
+        Granularity::Sentence => "s",
+        Granularity::Block => "b",
+        Granularity::Post => return post_id.to_string(),
+    };
+    format!("{}#{}-{}-{}", post_id, prefix, hash_content(content), occurrence)
+}
+
+// Hashes the normalized content of a unit.
+fn hash_content(content: &str) -> String {
+    let normalized = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    to_hex(&Sha256::digest(normalized.as_bytes())[..8])
+}
+
+/// Parses a range-based ID of a sentence or text block.
+///
+/// Returns the post ID, granularity, and range.
+/// Returns `None` if `id` is not a range-based ID.
+pub fn parse_range_id(id: &str) -> Option<(&str, Granularity, Range<usize>)> {
+    let (post_id, fragment) = id.split_once('#')?;
+    let (granularity, range) = match fragment.strip_prefix("block-") {
+        Some(range) => (Granularity::Block, range),
+        None => (Granularity::Sentence, fragment),
+    };
+    let (start, end) = range.split_once('-')?;
+    Some((post_id, granularity, start.parse().ok()?..end.parse().ok()?))
+}
+
+/// Assigns occurrence indices to units.
+///
+/// Units must be in order of appearance. Units of the same granularity and
+/// normalized content are numbered from zero.
+pub fn assign_occurrences(mut units: Vec<PostSentence>) -> Vec<PostSentence> {
+    let mut counts: HashMap<(Granularity, String), usize> = HashMap::new();
+    for unit in units.iter_mut() {
+        let key = (unit.granularity, hash_content(&unit.content));
+        let count = counts.entry(key).or_default();
+        unit.occurrence = *count;
+        *count += 1;
+    }
+    units
+}
+
+/// Part of a post where a unit comes from.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PostPart {
+    /// Content.
+    #[default]
+    Content,
+    /// Name.
+    Name,
+    /// Summary; e.g., content warning of a `Note`.
+    Summary,
+    /// Name of the attachment at an index; i.e., alt text.
+    Attachment(usize),
+}
+
+impl PostPart {
+    /// Returns if the part is the content.
+    pub fn is_content(&self) -> bool {
+        *self == PostPart::Content
+    }
 }
 
 impl PostSentence {
     /// Returns the ID of the sentence.
+    ///
+    /// - sentence: `{post_id}#{start}-{end}`
+    /// - text block: `{post_id}#block-{start}-{end}`
+    /// - whole post: `{post_id}`
+    /// - name: `{post_id}#name`
+    /// - summary: `{post_id}#summary`
     pub fn id(&self) -> String {
-        format!("{}#{}-{}", self.post_id, self.range.start, self.range.end)
+        match self.part {
+            PostPart::Name => return format!("{}#name", self.post_id),
+            PostPart::Summary => return format!("{}#summary", self.post_id),
+            PostPart::Attachment(index) => {
+                return format!("{}#attachment-{}", self.post_id, index);
+            },
+            PostPart::Content => {},
+        };
+        match self.granularity {
+            Granularity::Sentence => format!(
+                "{}#{}-{}",
+                self.post_id,
+                self.range.start,
+                self.range.end,
+            ),
+            Granularity::Block => format!(
+                "{}#block-{}-{}",
+                self.post_id,
+                self.range.start,
+                self.range.end,
+            ),
+            Granularity::Post => self.post_id.clone(),
+        }
+    }
+
+    /// Returns the ID of the sentence in a given scheme.
+    pub fn id_with(&self, scheme: IdScheme) -> String {
+        match (scheme, self.part) {
+            (IdScheme::Content, PostPart::Content) => make_content_id(
+                &self.post_id,
+                self.granularity,
+                &self.content,
+                self.occurrence,
+            ),
+            _ => self.id(),
+        }
+    }
+
+    /// Returns the input text for embedding.
+    ///
+    /// Surrounds the content with the context if any. The parent post and
+    /// title precede the sentences as separate paragraphs.
+    pub fn embedding_input(&self) -> String {
+        if let Some(context) = self.context.as_ref() {
+            let sentences: Vec<&str> = context.preceding
+                .iter()
+                .map(|s| s.as_str())
+                .chain(Some(self.content.as_str()))
+                .chain(context.following.iter().map(|s| s.as_str()))
+                .collect();
+            let sentences = sentences.join(" ");
+            let paragraphs: Vec<&str> = context.parent
+                .iter()
+                .chain(context.title.iter())
+                .map(|s| s.as_str())
+                .chain(Some(sentences.as_str()))
+                .collect();
+            paragraphs.join("\n\n")
+        } else {
+            self.content.clone()
+        }
+    }
+}
+
+/// Context of a sentence.
+#[derive(Clone, Debug, Default)]
+pub struct SentenceContext {
+    /// Snippet of the post that the post replies to; see
+    /// [`crate::threads::snippet_of`].
+    pub parent: Option<String>,
+    /// Title or heading of the section.
+    pub title: Option<String>,
+    /// Preceding sentences.
+    pub preceding: Vec<String>,
+    /// Following sentences.
+    pub following: Vec<String>,
+}
+
+/// Splits a post into units of given granularities.
+///
+/// The name of an `Article` or `Page`, the summary, and the alt texts of
+/// attachments of a post are separate units of the finest granularity among
+/// `granularities` except for a whole post, and precede the other units.
+/// Units of the content follow and are ordered by granularity in the order of
+/// `granularities`.
+///
+/// Returns no unit for an unsupported object type; e.g., `Tombstone`.
+pub fn split_post(
+    post: &Post,
+    granularities: &[Granularity],
+    line_break_mode: LineBreakMode,
+) -> Vec<PostSentence> {
+    if !post.is_supported() {
+        println!("skipping unsupported {} object: {}", post.type_, post.id);
+        return Vec::new();
+    }
+    let mut units: Vec<PostSentence> = Vec::new();
+    let finest = [Granularity::Sentence, Granularity::Block]
+        .into_iter()
+        .find(|g| granularities.contains(g));
+    if let Some(granularity) = finest {
+        if post.has_title() {
+            units.extend(make_part_unit(post, PostPart::Name, granularity));
+        }
+        units.extend(make_part_unit(post, PostPart::Summary, granularity));
+        for index in 0..post.attachment.len() {
+            units.extend(make_part_unit(
+                post,
+                PostPart::Attachment(index),
+                granularity,
+            ));
+        }
+    }
+    for granularity in granularities {
+        match granularity {
+            Granularity::Sentence => units.extend(
+                split_post_into_sentences(post, line_break_mode),
+            ),
+            Granularity::Block => units.extend(
+                split_post_into_blocks(post, line_break_mode),
+            ),
+            Granularity::Post => units.extend(
+                make_post_unit(post, line_break_mode),
+            ),
+        }
     }
+    units
 }
 
 /// Splits a post into sentences.
-pub fn split_post_into_sentences(post: Post) -> Vec<PostSentence> {
-    let content = if let Some(source) = post.source {
-        source.content
-    } else {
-        post.content
-    };
-    extract_text_blocks(&content)
+///
+/// `line_break_mode` specifies how a line break in a paragraph is treated.
+pub fn split_post_into_sentences(
+    post: &Post,
+    line_break_mode: LineBreakMode,
+) -> Vec<PostSentence> {
+    let sentences = extract_text_blocks(post.text())
         .unwrap()
         .into_iter()
-        .flat_map(|block| extract_sentences(&block))
+        .flat_map(|block| extract_sentences(&block, line_break_mode))
         .map(|(sentence, range)| PostSentence {
             post_id: post.id.clone(),
             content: sentence,
             range,
+            granularity: Granularity::Sentence,
+            part: PostPart::Content,
+            occurrence: 0,
+            context: None,
+            metadata: post.metadata(),
+        })
+        .collect();
+    assign_occurrences(sentences)
+}
+
+/// Splits a post into text blocks.
+///
+/// The content of a text block is its sentences joined with a space.
+pub fn split_post_into_blocks(
+    post: &Post,
+    line_break_mode: LineBreakMode,
+) -> Vec<PostSentence> {
+    let blocks = extract_text_blocks(post.text())
+        .unwrap()
+        .into_iter()
+        .filter_map(|block| {
+            let range = match &block {
+                TextBlock::Text(fragments) => {
+                    let start = fragments.first()?.1.start;
+                    let end = fragments.last()?.1.end;
+                    start..end
+                },
+                TextBlock::Code { range, .. } => range.clone(),
+            };
+            let content = extract_sentences(&block, line_break_mode)
+                .into_iter()
+                .map(|(sentence, _)| sentence)
+                .collect::<Vec<_>>()
+                .join(" ");
+            Some(PostSentence {
+                post_id: post.id.clone(),
+                content,
+                range,
+                granularity: Granularity::Block,
+                part: PostPart::Content,
+                occurrence: 0,
+                context: None,
+                metadata: post.metadata(),
+            })
+        })
+        .filter(|block| !block.content.is_empty())
+        .collect();
+    assign_occurrences(blocks)
+}
+
+/// Makes a unit of a whole post.
+///
+/// The content consists of the name, summary, and text blocks separated by
+/// line breaks.
+/// Returns `None` if the post has no content.
+pub fn make_post_unit(
+    post: &Post,
+    line_break_mode: LineBreakMode,
+) -> Option<PostSentence> {
+    let blocks = split_post_into_blocks(post, line_break_mode);
+    let content = post.name
+        .as_deref()
+        .filter(|_| post.has_title())
+        .into_iter()
+        .chain(post.summary.as_deref())
+        .chain(blocks.iter().map(|block| block.content.as_str()))
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if content.is_empty() {
+        return None;
+    }
+    Some(PostSentence {
+        post_id: post.id.clone(),
+        content,
+        range: 0..post.text().len(),
+        granularity: Granularity::Post,
+        part: PostPart::Content,
+        occurrence: 0,
+        context: None,
+        metadata: post.metadata(),
+    })
+}
+
+/// Makes a unit of the name, summary, or alt text of an attachment of a post.
+///
+/// Returns `None` if the post has no such part.
+/// `part` must not be [`PostPart::Content`].
+pub fn make_part_unit(
+    post: &Post,
+    part: PostPart,
+    granularity: Granularity,
+) -> Option<PostSentence> {
+    let text = match part {
+        PostPart::Name => post.name.as_ref(),
+        PostPart::Summary => post.summary.as_ref(),
+        PostPart::Attachment(index) => {
+            post.attachment.get(index)?.name.as_ref()
+        },
+        PostPart::Content => None,
+    }?;
+    let content = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if content.is_empty() {
+        return None;
+    }
+    Some(PostSentence {
+        post_id: post.id.clone(),
+        content,
+        range: 0..text.len(),
+        granularity,
+        part,
+        occurrence: 0,
+        context: None,
+        metadata: post.metadata(),
+    })
+}
+
+/// Window of a context included in the embedding input of a sentence.
+#[derive(Clone, Debug, Default)]
+pub struct ContextWindow {
+    /// Number of preceding sentences.
+    pub before: usize,
+    /// Number of following sentences.
+    pub after: usize,
+    /// Whether to include the nearest preceding heading.
+    pub title: bool,
+}
+
+impl ContextWindow {
+    /// Returns if the window includes no context.
+    pub fn is_empty(&self) -> bool {
+        self.before == 0 && self.after == 0 && !self.title
+    }
+}
+
+/// Attaches contexts to sentences of a post.
+///
+/// `sentences` must be sentences of `post` in order of appearance.
+/// Neighboring sentences are taken from `sentences`, so sentences dropped
+/// by a [`SentenceFilter`] never appear in a context.
+/// Units other than sentences in the content are left intact and never
+/// appear in a context.
+///
+/// The title is the nearest preceding heading, or the name of an `Article`
+/// or `Page` if there is no such heading. If the window includes the title,
+/// sentences of headings are left intact and never appear in a context,
+/// because they are already titles.
+pub fn attach_context(
+    post: &Post,
+    mut sentences: Vec<PostSentence>,
+    window: &ContextWindow,
+) -> Vec<PostSentence> {
+    if window.is_empty() {
+        return sentences;
+    }
+    let headings = if window.title {
+        extract_headings(post.text()).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    // sentences and headings are in the same byte offsets of the text
+    let is_content_sentence = |s: &PostSentence| {
+        s.granularity == Granularity::Sentence
+            && s.part == PostPart::Content
+            && !headings.iter().any(|(_, range)| {
+                range.start <= s.range.start && s.range.end <= range.end
+            })
+    };
+    let contents: Vec<String> = sentences
+        .iter()
+        .filter(|s| is_content_sentence(s))
+        .map(|s| s.content.clone())
+        .collect();
+    for (i, sentence) in sentences
+        .iter_mut()
+        .filter(|s| is_content_sentence(s))
+        .enumerate()
+    {
+        // falls back to the title of the post
+        let title = headings
+            .iter()
+            .take_while(|(_, range)| range.end <= sentence.range.start)
+            .last()
+            .map(|(heading, _)| heading.clone())
+            .or_else(|| post.name.clone().filter(|_| post.has_title()));
+        let preceding = contents[i.saturating_sub(window.before)..i].to_vec();
+        let following = contents
+            .iter()
+            .skip(i + 1)
+            .take(window.after)
+            .cloned()
+            .collect();
+        let context = sentence.context.get_or_insert_with(Default::default);
+        context.title = title;
+        context.preceding = preceding;
+        context.following = following;
+    }
+    sentences
+}
+
+/// Rule-based filter that drops trivial or noisy sentences.
+///
+/// A sentence is dropped if any of the following holds:
+/// - it has fewer non-whitespace characters than `min_chars`
+/// - it has fewer tokens than `min_tokens`
+/// - the ratio of alphabetic or ideographic characters to non-whitespace
+///   characters is less than `min_letter_ratio`
+/// - it matches an entry in `stop_list`
+///
+/// A token is a run of non-whitespace characters, except that every
+/// ideographic or kana character is counted as a token by itself. A URL is a
+/// single token, and its characters are not counted.
+///
+/// The default filter accepts every sentence. See
+/// [`SentenceFilter::recommended`] for thresholds that drop trivial ones.
+#[derive(Clone, Debug)]
+pub struct SentenceFilter {
+    /// Minimum number of non-whitespace characters.
+    pub min_chars: usize,
+    /// Minimum number of tokens.
+    pub min_tokens: usize,
+    /// Minimum ratio of alphabetic or ideographic characters.
+    pub min_letter_ratio: f64,
+    /// Sentences to be dropped.
+    ///
+    /// Comparison is case-insensitive and ignores surrounding whitespaces and
+    /// punctuations.
+    pub stop_list: Vec<String>,
+}
+
+impl Default for SentenceFilter {
+    fn default() -> Self {
+        Self {
+            min_chars: 0,
+            min_tokens: 0,
+            min_letter_ratio: 0.0,
+            stop_list: Vec::new(),
+        }
+    }
+}
+
+/// Sentences that passed a [`SentenceFilter`].
+#[derive(Clone, Debug)]
+pub struct FilteredSentences {
+    /// Sentences that passed the filter.
+    pub sentences: Vec<PostSentence>,
+    /// Number of dropped sentences.
+    pub num_dropped: usize,
+}
+
+impl SentenceFilter {
+    /// Returns a filter that drops emoji-only, single-word, and
+    /// punctuation-only sentences.
+    pub fn recommended() -> Self {
+        Self {
+            min_chars: 4,
+            min_tokens: 2,
+            min_letter_ratio: 0.5,
+            stop_list: Vec::new(),
+        }
+    }
+
+    /// Sets the stop-list.
+    ///
+    /// Entries are normalized in the same way as sentences.
+    pub fn with_stop_list<I, S>(mut self, stop_list: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.stop_list = stop_list
+            .into_iter()
+            .map(|s| normalize_for_stop_list(s.as_ref()))
+            .filter(|s| !s.is_empty())
+            .collect();
+        self
+    }
+
+    /// Applies the filter to sentences of a post.
+    pub fn apply(&self, sentences: Vec<PostSentence>) -> FilteredSentences {
+        let num_sentences = sentences.len();
+        let sentences: Vec<PostSentence> = sentences
+            .into_iter()
+            .filter(|s| self.accepts(&s.content))
+            .collect();
+        FilteredSentences {
+            num_dropped: num_sentences - sentences.len(),
+            sentences,
+        }
+    }
+
+    /// Returns if a given sentence passes the filter.
+    pub fn accepts(&self, sentence: &str) -> bool {
+        let mut num_chars = 0usize;
+        let mut num_letters = 0usize;
+        let mut num_tokens = 0usize;
+        for word in sentence.split_whitespace() {
+            if word.starts_with("http://") || word.starts_with("https://") {
+                num_tokens += 1;
+                continue;
+            }
+            let mut in_token = false;
+            for ch in word.chars() {
+                num_chars += 1;
+                if is_ideographic(ch) {
+                    num_tokens += 1;
+                    in_token = false;
+                    num_letters += 1;
+                } else {
+                    if !in_token {
+                        num_tokens += 1;
+                        in_token = true;
+                    }
+                    if ch.is_alphabetic() {
+                        num_letters += 1;
+                    }
+                }
+            }
+        }
+        if num_chars < self.min_chars {
+            return false;
+        }
+        if num_tokens < self.min_tokens {
+            return false;
+        }
+        if (num_letters as f64) < self.min_letter_ratio * num_chars as f64 {
+            return false;
+        }
+        if !self.stop_list.is_empty() {
+            let normalized = normalize_for_stop_list(sentence);
+            if self.stop_list.contains(&normalized) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Returns if a given character is an ideograph or kana.
+fn is_ideographic(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{3040}'..='\u{30FF}' // hiragana and katakana
+        | '\u{3400}'..='\u{4DBF}' // CJK extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK unified ideographs
+        | '\u{F900}'..='\u{FAFF}' // CJK compatibility ideographs
+        | '\u{AC00}'..='\u{D7AF}' // hangul syllables
+        | '\u{20000}'..='\u{2FFFF}' // CJK extensions B and later
+    )
+}
+
+// Normalizes a sentence for comparison with a stop-list.
+fn normalize_for_stop_list(sentence: &str) -> String {
+    sentence
+        .trim_matches(|c: char| {
+            c.is_whitespace()
+                || c.is_ascii_punctuation()
+                || matches!(c, '。' | '、' | '！' | '？')
         })
-        .collect()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
 }
 
+/// Version of the schema of [`Embedding`].
+///
+/// - 0: `id`, `content`, and `embedding` with optional `granularity`,
+///   `range`, `metadata`, and `part`. Made only by [`V0_EMBEDDING_MODEL`].
+/// - 1: adds the provenance; i.e., model, dimensions, post ID, language,
+///   published date, content hash, creation date, and pipeline version.
+pub const EMBEDDING_SCHEMA_VERSION: u32 = 1;
+
+/// Model that made embeddings of schema version 0.
+pub const V0_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/// Version of the pipeline that makes embeddings.
+pub const PIPELINE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
 /// Embedding of a content.
+///
+/// Use [`read_embedding`] to load a file of any schema version.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Embedding {
+    /// Schema version; see [`EMBEDDING_SCHEMA_VERSION`].
+    ///
+    /// 0 if omitted.
+    #[serde(default)]
+    pub version: u32,
     /// ID of the source object.
     pub id: String,
     /// Content that produced the embedding.
     pub content: String,
     /// Embedding vector.
-    pub embedding: Vec<f64>,
+    pub embedding: Vec<f32>,
+    /// Granularity of the content.
+    ///
+    /// Sentence if omitted.
+    #[serde(default)]
+    pub granularity: Granularity,
+    /// Range of the content in the post.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range<usize>>,
+    /// Metadata of the source post.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<PostMetadata>,
+    /// Part of the post where the content comes from.
+    ///
+    /// Content if omitted.
+    #[serde(default, skip_serializing_if = "PostPart::is_content")]
+    pub part: PostPart,
+    /// Model that made the embedding.
+    #[serde(default)]
+    pub model: String,
+    /// Number of dimensions of the embedding vector.
+    #[serde(default)]
+    pub dimensions: usize,
+    /// ID of the source post.
+    #[serde(default)]
+    pub post_id: String,
+    /// Language tag of the source post.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Published date of the source post.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<DateTime<Utc>>,
+    /// Hex-encoded SHA-256 digest of the normalized input that produced the
+    /// embedding; see [`hash_input`].
+    ///
+    /// `None` for schema version 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// Creation date.
+    ///
+    /// `None` for schema version 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Version of the pipeline that made the embedding; see
+    /// [`PIPELINE_VERSION`].
+    ///
+    /// `None` for schema version 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline_version: Option<String>,
+}
+
+impl Embedding {
+    /// Returns if the content is hidden behind a content warning.
+    ///
+    /// The content and attachments of a post with a content warning are
+    /// hidden, whereas the content warning itself is not.
+    pub fn is_hidden_by_cw(&self) -> bool {
+        matches!(self.part, PostPart::Content | PostPart::Attachment(_))
+            && self.metadata
+                .as_ref()
+                .is_some_and(|metadata| metadata.has_content_warning())
+    }
+
+    /// Returns the published date of the source post.
+    pub fn published(&self) -> Option<DateTime<Utc>> {
+        self.published.or_else(|| self.metadata.as_ref()?.published)
+    }
+
+    // Fills the provenance that schema version 0 derives from the other
+    // fields.
+    fn upgrade_v0(&mut self) {
+        self.model = V0_EMBEDDING_MODEL.to_string();
+        self.dimensions = self.embedding.len();
+        self.post_id = self.id
+            .split_once('#')
+            .map_or(self.id.as_str(), |(post_id, _)| post_id)
+            .to_string();
+        if let Some(metadata) = self.metadata.as_ref() {
+            self.language = metadata.language.clone();
+            self.published = metadata.published;
+        }
+    }
+
+    /// Returns the search scope of the embedding under a given policy.
+    pub fn search_scope(&self, policy: CwPolicy) -> &'static str {
+        match policy {
+            CwPolicy::Exclude if self.is_hidden_by_cw() => CW_SCOPE,
+            _ => DEFAULT_SCOPE,
+        }
+    }
+}
+
+/// Reads an embedding of any supported schema version.
+///
+/// Fills the provenance of schema version 0 that can be derived, and checks
+/// the dimensions otherwise. Fails if the schema version is newer than
+/// [`EMBEDDING_SCHEMA_VERSION`].
+pub fn read_embedding(reader: impl std::io::Read) -> Result<Embedding, Error> {
+    let mut embedding: Embedding = serde_json::from_reader(reader)?;
+    match embedding.version {
+        0 => embedding.upgrade_v0(),
+        v if v > EMBEDDING_SCHEMA_VERSION => {
+            return Err(Error::InvalidData(format!(
+                "unsupported embedding schema version: {}",
+                v,
+            )));
+        },
+        _ => {
+            if embedding.dimensions != embedding.embedding.len() {
+                return Err(Error::InvalidData(format!(
+                    "embedding has {} dimensions but {} values",
+                    embedding.dimensions,
+                    embedding.embedding.len(),
+                )));
+            }
+        },
+    }
+    Ok(embedding)
+}
+
+/// Hashes a normalized embedding input as a hex-encoded SHA-256 digest.
+pub fn hash_input(input: &str) -> String {
+    to_hex(&Sha256::digest(input.as_bytes()))
+}
+
+/// Search scope that queries cover by default.
+///
+/// Used as a value of the "scope" attribute in a database. A vector without
+/// the attribute is in the default scope.
+pub const DEFAULT_SCOPE: &str = "default";
+
+/// Search scope of contents hidden behind content warnings.
+///
+/// Used as a value of the "scope" attribute in a database. Queries cover
+/// this scope only if asked.
+pub const CW_SCOPE: &str = "cw";
+
+/// Policy on contents hidden behind content warnings.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CwPolicy {
+    /// Includes hidden contents in the default search scope.
+    #[default]
+    Include,
+    /// Excludes hidden contents from the default search scope.
+    Exclude,
+}
+
+impl FromStr for CwPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "include" => Ok(CwPolicy::Include),
+            "exclude" => Ok(CwPolicy::Exclude),
+            _ => Err(Error::InvalidData(format!("unknown CW policy: {}", s))),
+        }
+    }
 }
 
 /// Creates embeddings for given sentences.
+///
+/// The embedding input of a sentence includes its context if it has one
+/// attached by [`attach_context`], whereas the ID and content of the
+/// resulting [`Embedding`] refer only to the sentence itself.
+///
+/// IDs of embeddings are in `id_scheme`.
+///
+/// Inputs are given to `provider` as they are. Inputs that are the same
+/// after normalization by [`normalize_text`] are embedded only once.
+/// Embeddings are reused from and saved in `cache` if it is given.
+/// Inputs are truncated to [`EmbeddingProvider::max_input_tokens`], and an
+/// input that still exceeds the context length of the model is truncated
+/// further.
 pub async fn create_embeddings_for_sentences(
     sentences: Vec<PostSentence>,
-    api_key: String,
+    provider: &dyn EmbeddingProvider,
+    id_scheme: IdScheme,
+    cache: Option<&EmbeddingCache>,
 ) -> Result<Vec<Embedding>, Error> {
-    let request = EmbeddingRequestBody {
-        model: format!("text-embedding-ada-002"),
-        input: sentences.iter().map(|s| s.content.clone()).collect(),
-        user: Some(format!("mumble_embedding")),
-    };
-    let res = create_embeddings(&request, api_key).await?;
-    println!("usage: {:?}", res.usage);
-    let mut data = res.data;
-    if sentences.len() != data.len() {
-        return Err(Error::InvalidData(
-            format!("failed to create embeddings of one or more posts"),
-        ));
-    }
-    data.sort_by_key(|d| d.index);
+    let max_tokens = provider.max_input_tokens();
+    let inputs: Vec<String> = sentences
+        .iter()
+        .map(|s| {
+            let input = s.embedding_input();
+            match max_tokens {
+                Some(max_tokens) =>
+                    truncate_to_tokens(&input, max_tokens).to_string(),
+                None => input,
+            }
+        })
+        .collect();
+    // normalized inputs identify duplicates
+    let keys: Vec<String> = inputs
+        .iter()
+        .map(|input| normalize_text(input))
+        .collect();
+    // resolves embeddings from the cache, and collects unique inputs to be
+    // embedded
+    let model = provider.model_id();
+    let mut vectors: HashMap<&str, Vec<f32>> = HashMap::new();
+    let mut pending_keys: HashSet<&str> = HashSet::new();
+    let mut pending: Vec<(&str, &str)> = Vec::new();
+    for (input, key) in inputs.iter().zip(keys.iter()) {
+        if vectors.contains_key(key.as_str()) || pending_keys.contains(&**key) {
+            if let Some(cache) = cache {
+                cache.record_hit();
+            }
+            continue;
+        }
+        let cached = match cache {
+            Some(cache) => cache.get(model, input)?,
+            None => None,
+        };
+        match cached {
+            Some(vector) => {
+                vectors.insert(key, vector);
+            },
+            None => {
+                pending_keys.insert(key);
+                pending.push((key, input));
+            },
+        };
+    }
+    if !pending.is_empty() {
+        let pending_inputs: Vec<String> =
+            pending.iter().map(|(_, input)| input.to_string()).collect();
+        let data = embed_within_context(provider, &pending_inputs).await?;
+        if pending.len() != data.len() {
+            return Err(Error::InvalidData(
+                "failed to create embeddings of one or more posts".to_string(),
+            ));
+        }
+        for ((key, input), vector) in pending.into_iter().zip(data) {
+            if let Some(cache) = cache {
+                cache.put(model, input, &vector)?;
+            }
+            vectors.insert(key, vector);
+        }
+    }
+    let created_at = Utc::now();
     let embeddings = sentences.into_iter()
-        .zip(request.input.into_iter())
-        .zip(data.into_iter())
-        .map(|((s, content), d)| Embedding {
-            id: s.id(),
-            content,
-            embedding: d.embedding,
+        .zip(keys.iter())
+        .map(|(s, key)| {
+            let embedding = vectors[key.as_str()].clone();
+            Embedding {
+                version: EMBEDDING_SCHEMA_VERSION,
+                id: s.id_with(id_scheme),
+                content: s.content,
+                granularity: s.granularity,
+                range: Some(s.range),
+                part: s.part,
+                model: model.to_string(),
+                dimensions: embedding.len(),
+                embedding,
+                post_id: s.post_id,
+                language: s.metadata.language.clone(),
+                published: s.metadata.published,
+                content_hash: Some(hash_input(key)),
+                created_at: Some(created_at),
+                pipeline_version: Some(PIPELINE_VERSION.to_string()),
+                metadata: Some(s.metadata),
+            }
         })
         .collect();
     Ok(embeddings)
 }
+
+// Embeds given inputs.
+//
+// If an input exceeds the context length of the model, embeds the inputs one
+// by one, and truncates an input that still exceeds it to half its tokens
+// until it fits.
+async fn embed_within_context(
+    provider: &dyn EmbeddingProvider,
+    inputs: &[String],
+) -> Result<Vec<Vec<f32>>, Error> {
+    match provider.embed(inputs).await {
+        Err(e) if e.is_context_length_exceeded() && inputs.len() > 1 => {
+            println!(
+                "context length exceeded: embedding {} inputs one by one",
+                inputs.len(),
+            );
+            let mut data = Vec::with_capacity(inputs.len());
+            for input in inputs {
+                data.push(embed_truncating(provider, input).await?);
+            }
+            Ok(data)
+        },
+        Err(e) if e.is_context_length_exceeded() => {
+            Ok(vec![embed_truncating(provider, &inputs[0]).await?])
+        },
+        res => res,
+    }
+}
+
+// Embeds a given input, and truncates it while it exceeds the context length
+// of the model.
+async fn embed_truncating(
+    provider: &dyn EmbeddingProvider,
+    input: &str,
+) -> Result<Vec<f32>, Error> {
+    let mut truncated = input;
+    loop {
+        match provider.embed(&[truncated.to_string()]).await {
+            Ok(mut data) if data.len() == 1 => return Ok(data.remove(0)),
+            Ok(_) => return Err(Error::InvalidData(
+                "failed to create the embedding of an input".to_string(),
+            )),
+            Err(e) if e.is_context_length_exceeded() => {
+                let shorter = truncate_to_tokens(
+                    truncated,
+                    estimate_tokens(truncated) / 2,
+                );
+                // gives up if the input cannot be shorter
+                if shorter.is_empty() || shorter.len() == truncated.len() {
+                    return Err(e);
+                }
+                println!(
+                    "WARNING: truncated an input to {} bytes: {}",
+                    shorter.len(),
+                    shorter.chars().take(40).collect::<String>(),
+                );
+                truncated = shorter;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentence_filter_drops_trivial_sentences() {
+        let filter = SentenceFilter::recommended();
+        assert!(!filter.accepts("👍"));
+        assert!(!filter.accepts("Nice!"));
+        assert!(!filter.accepts("https://example.com/some/long/path"));
+        assert!(!filter.accepts("!!!???"));
+        assert!(filter.accepts("The cache broke again."));
+        assert!(filter.accepts("キャッシュが壊れた。"));
+        // URLs count neither as letters nor as other characters
+        assert!(filter.accepts("#rust see https://example.com/a_b"));
+
+        let filter = SentenceFilter::default();
+        assert!(filter.accepts("👍"));
+        assert!(filter.accepts("https://example.com/some/long/path"));
+    }
+
+    #[test]
+    fn post_filter_checks_date_audience_and_replies() {
+        let post: Post = serde_json::from_str(r#"{
+            "id": "https://example.com/posts/1",
+            "type": "Note",
+            "published": "2023-09-20T09:00:00+09:00",
+            "to": "https://www.w3.org/ns/activitystreams#Public",
+            "cc": ["https://example.com/users/test/followers"]
+        }"#).unwrap();
+        let date = |s: &str| Some(s.parse::<DateTime<Utc>>().unwrap());
+        let filter = PostFilter::default();
+        assert!(filter.matches(&post));
+        let filter = PostFilter {
+            since: date("2023-09-20T00:00:00Z"),
+            until: date("2023-09-21T00:00:00Z"),
+            ..PostFilter::default()
+        };
+        assert!(filter.matches(&post));
+        let filter = PostFilter {
+            since: date("2023-09-20T00:00:01Z"),
+            ..PostFilter::default()
+        };
+        assert!(!filter.matches(&post));
+        let filter = PostFilter {
+            until: date("2023-09-20T00:00:00Z"),
+            ..PostFilter::default()
+        };
+        assert!(!filter.matches(&post));
+        let filter = PostFilter {
+            replies: ReplyFilter::Only,
+            ..PostFilter::default()
+        };
+        assert!(!filter.matches(&post));
+
+        let followers_only = Post {
+            to: vec!["https://example.com/users/test/followers".to_string()],
+            in_reply_to: Some("https://example.com/posts/0".to_string()),
+            ..post
+        };
+        assert!(!PostFilter::default().matches(&followers_only));
+        let filter = PostFilter {
+            public_only: false,
+            replies: ReplyFilter::Exclude,
+            ..PostFilter::default()
+        };
+        assert!(!filter.matches(&followers_only));
+        let filter = PostFilter {
+            public_only: false,
+            replies: ReplyFilter::Only,
+            ..PostFilter::default()
+        };
+        assert!(filter.matches(&followers_only));
+    }
+
+    #[test]
+    fn attach_context_includes_neighbors_and_heading() {
+        let post = Post {
+            id: "https://example.com/posts/1".to_string(),
+            type_: "Note".to_string(),
+            content: "# Cache\n\nI restarted it. It broke again. No idea."
+                .to_string(),
+            published: "2023-09-20T00:00:00Z".parse().ok(),
+            ..Post::default()
+        };
+        let sentences = split_post_into_sentences(
+            &post,
+            LineBreakMode::Whitespace,
+        );
+        let sentences = attach_context(&post, sentences, &ContextWindow {
+            before: 1,
+            after: 1,
+            title: true,
+        });
+        assert_eq!(sentences[2].content, "It broke again.");
+        assert_eq!(
+            sentences[2].embedding_input(),
+            "Cache\n\nI restarted it. It broke again. No idea.",
+        );
+        assert_eq!(
+            sentences[1].embedding_input(),
+            "Cache\n\nI restarted it. It broke again.",
+        );
+        // the heading is only the title
+        assert_eq!(sentences[0].embedding_input(), "Cache");
+    }
+
+    #[test]
+    fn attach_context_finds_headings_of_non_ascii_text() {
+        let post = Post {
+            id: "https://example.com/posts/1".to_string(),
+            type_: "Note".to_string(),
+            content: "# 天気\n\n今日は晴れ。\n\n# 予定\n\n明日は雨。"
+                .to_string(),
+            ..Post::default()
+        };
+        let sentences = split_post_into_sentences(
+            &post,
+            LineBreakMode::Whitespace,
+        );
+        let sentences = attach_context(&post, sentences, &ContextWindow {
+            before: 1,
+            after: 0,
+            title: true,
+        });
+        let inputs: Vec<String> = sentences
+            .iter()
+            .map(|s| s.embedding_input())
+            .collect();
+        assert_eq!(inputs, vec![
+            "天気",
+            "天気\n\n今日は晴れ。",
+            "予定",
+            "予定\n\n今日は晴れ。 明日は雨。",
+        ]);
+    }
+
+    #[test]
+    fn split_post_makes_units_of_each_granularity() {
+        let post = Post {
+            id: "https://example.com/posts/1".to_string(),
+            type_: "Note".to_string(),
+            content: "First one. Second one.\n\nThird one.".to_string(),
+            published: "2023-09-20T00:00:00Z".parse().ok(),
+            ..Post::default()
+        };
+        let units = split_post(
+            &post,
+            &[Granularity::Post, Granularity::Block, Granularity::Sentence],
+            LineBreakMode::Whitespace,
+        );
+        let ids: Vec<String> = units.iter().map(|u| u.id()).collect();
+        assert_eq!(ids, vec![
+            "https://example.com/posts/1",
+            "https://example.com/posts/1#block-0-22",
+            "https://example.com/posts/1#block-24-34",
+            "https://example.com/posts/1#0-10",
+            "https://example.com/posts/1#10-22",
+            "https://example.com/posts/1#24-34",
+        ]);
+        assert_eq!(
+            units[0].content,
+            "First one. Second one.\nThird one.",
+        );
+        assert_eq!(units[1].content, "First one. Second one.");
+    }
+
+    #[test]
+    fn split_post_handles_object_types() {
+        let article: Post = serde_json::from_str(r##"{
+            "id": "https://example.com/posts/2",
+            "type": "Article",
+            "name": "Caching",
+            "summary": "How I fixed the cache.",
+            "content": "It was the TTL.",
+            "published": "2023-09-20T00:00:00Z",
+            "tag": { "type": "Hashtag", "name": "#cache" }
+        }"##).unwrap();
+        let units = split_post(
+            &article,
+            &[Granularity::Sentence],
+            LineBreakMode::Whitespace,
+        );
+        let ids: Vec<String> = units.iter().map(|u| u.id()).collect();
+        assert_eq!(ids, vec![
+            "https://example.com/posts/2#name",
+            "https://example.com/posts/2#summary",
+            "https://example.com/posts/2#0-15",
+        ]);
+        assert_eq!(units[2].metadata.tag[0].name.as_deref(), Some("#cache"));
+
+        let tombstone: Post = serde_json::from_str(r#"{
+            "id": "https://example.com/posts/3",
+            "type": "Tombstone"
+        }"#).unwrap();
+        assert!(split_post(
+            &tombstone,
+            &[Granularity::Sentence],
+            LineBreakMode::Whitespace,
+        ).is_empty());
+    }
+
+    #[test]
+    fn split_post_makes_units_of_alt_texts_and_cw() {
+        let note: Post = serde_json::from_str(r#"{
+            "id": "https://example.com/posts/4",
+            "type": "Note",
+            "summary": "Food",
+            "content": "I cooked curry tonight.",
+            "attachment": [
+                { "type": "Image", "url": "https://example.com/1.png" },
+                {
+                    "type": "Image",
+                    "url": "https://example.com/2.png",
+                    "name": "A bowl of curry"
+                }
+            ]
+        }"#).unwrap();
+        let units = split_post(
+            &note,
+            &[Granularity::Sentence],
+            LineBreakMode::Whitespace,
+        );
+        let ids: Vec<String> = units.iter().map(|u| u.id()).collect();
+        assert_eq!(ids, vec![
+            "https://example.com/posts/4#summary",
+            "https://example.com/posts/4#attachment-1",
+            "https://example.com/posts/4#0-23",
+        ]);
+        assert_eq!(units[1].content, "A bowl of curry");
+
+        let scopes: Vec<&str> = units.into_iter()
+            .map(|unit| Embedding {
+                version: EMBEDDING_SCHEMA_VERSION,
+                id: unit.id(),
+                content: unit.content,
+                embedding: Vec::new(),
+                granularity: unit.granularity,
+                range: Some(unit.range),
+                part: unit.part,
+                model: V0_EMBEDDING_MODEL.to_string(),
+                dimensions: 0,
+                post_id: unit.post_id,
+                language: None,
+                published: None,
+                content_hash: None,
+                created_at: None,
+                pipeline_version: None,
+                metadata: Some(unit.metadata),
+            })
+            .map(|embedding| embedding.search_scope(CwPolicy::Exclude))
+            .collect();
+        assert_eq!(scopes, vec![DEFAULT_SCOPE, CW_SCOPE, CW_SCOPE]);
+    }
+
+    #[test]
+    fn read_embedding_accepts_v0_and_rejects_newer_versions() {
+        let v0 = r#"{
+            "id": "https://example.com/posts/1#0-10",
+            "content": "Hello.",
+            "embedding": [0.5, 0.5],
+            "metadata": {
+                "type": "Note",
+                "published": "2023-09-20T00:00:00Z",
+                "language": "en"
+            }
+        }"#;
+        let embedding = read_embedding(v0.as_bytes()).unwrap();
+        assert_eq!(embedding.version, 0);
+        assert_eq!(embedding.model, V0_EMBEDDING_MODEL);
+        assert_eq!(embedding.dimensions, 2);
+        assert_eq!(embedding.post_id, "https://example.com/posts/1");
+        assert_eq!(embedding.language.as_deref(), Some("en"));
+        assert!(embedding.published.is_some());
+        assert!(embedding.content_hash.is_none());
+
+        let mut v1 = embedding.clone();
+        v1.version = EMBEDDING_SCHEMA_VERSION;
+        v1.content_hash = Some(hash_input("Hello."));
+        let json = serde_json::to_string(&v1).unwrap();
+        assert!(json.contains(r#""postId":"https://example.com/posts/1""#));
+        let v1 = read_embedding(json.as_bytes()).unwrap();
+        assert_eq!(v1.content_hash, Some(hash_input("Hello.")));
+
+        let newer = json.replace(
+            &format!(r#""version":{}"#, EMBEDDING_SCHEMA_VERSION),
+            r#""version":99"#,
+        );
+        assert!(read_embedding(newer.as_bytes()).is_err());
+        let mismatched = json.replace(r#""dimensions":2"#, r#""dimensions":3"#);
+        assert!(read_embedding(mismatched.as_bytes()).is_err());
+    }
+
+    // Provider that embeds a text into its length, and records inputs.
+    struct FakeProvider {
+        inputs: std::cell::RefCell<Vec<String>>,
+    }
+
+    impl EmbeddingProvider for FakeProvider {
+        fn embed<'a>(
+            &'a self,
+            inputs: &'a [String],
+        ) -> futures::future::LocalBoxFuture<'a, Result<Vec<Vec<f32>>, Error>>
+        {
+            self.inputs.borrow_mut().extend(inputs.iter().cloned());
+            let vectors = inputs.iter().map(|s| vec![s.len() as f32]).collect();
+            Box::pin(async move { Ok(vectors) })
+        }
+
+        fn model_id(&self) -> &str {
+            "fake-model"
+        }
+
+        fn dimensions(&self) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn create_embeddings_for_sentences_embeds_unique_inputs() {
+        let post = Post {
+            id: "https://example.com/posts/1".to_string(),
+            type_: "Note".to_string(),
+            content: "Same here. Same  here. Done.".to_string(),
+            ..Post::default()
+        };
+        let mut sentences =
+            split_post_into_sentences(&post, LineBreakMode::Whitespace);
+        sentences[2].context = Some(SentenceContext {
+            title: Some("Status".to_string()),
+            ..SentenceContext::default()
+        });
+        let provider = FakeProvider { inputs: Default::default() };
+        let embeddings = create_embeddings_for_sentences(
+            sentences,
+            &provider,
+            IdScheme::Range,
+            None,
+        ).await.unwrap();
+        // separators in inputs are kept
+        assert_eq!(
+            *provider.inputs.borrow(),
+            vec!["Same here.", "Status\n\nDone."],
+        );
+        let vectors: Vec<_> = embeddings.iter()
+            .map(|e| e.embedding.clone())
+            .collect();
+        assert_eq!(vectors, vec![vec![10.0], vec![10.0], vec![13.0]]);
+        assert!(embeddings.iter().all(|e| e.model == "fake-model"));
+        assert_eq!(embeddings[0].post_id, post.id);
+    }
+
+    // Provider that rejects inputs longer than a given number of bytes.
+    struct LimitedProvider {
+        max_len: usize,
+        max_tokens: Option<usize>,
+        requests: std::cell::RefCell<Vec<Vec<String>>>,
+    }
+
+    impl EmbeddingProvider for LimitedProvider {
+        fn embed<'a>(
+            &'a self,
+            inputs: &'a [String],
+        ) -> futures::future::LocalBoxFuture<'a, Result<Vec<Vec<f32>>, Error>>
+        {
+            self.requests.borrow_mut().push(inputs.to_vec());
+            let result = if inputs.iter().any(|s| s.len() > self.max_len) {
+                let e = crate::openai::ApiError::from_body(r#"{
+                    "error": {
+                        "message": "Too long.",
+                        "type": "invalid_request_error",
+                        "code": "context_length_exceeded"
+                    }
+                }"#).unwrap();
+                Err(Error::OpenAiError(reqwest::StatusCode::BAD_REQUEST, e))
+            } else {
+                Ok(inputs.iter().map(|s| vec![s.len() as f32]).collect())
+            };
+            Box::pin(async move { result })
+        }
+
+        fn model_id(&self) -> &str {
+            "limited-model"
+        }
+
+        fn dimensions(&self) -> usize {
+            1
+        }
+
+        fn max_input_tokens(&self) -> Option<usize> {
+            self.max_tokens
+        }
+    }
+
+    #[tokio::test]
+    async fn create_embeddings_for_sentences_truncates_too_long_inputs() {
+        let post = Post {
+            id: "https://example.com/posts/1".to_string(),
+            type_: "Note".to_string(),
+            content: "Short. This sentence is way too long.".to_string(),
+            ..Post::default()
+        };
+        let sentences =
+            split_post_into_sentences(&post, LineBreakMode::Whitespace);
+        let provider = LimitedProvider {
+            max_len: 20,
+            max_tokens: None,
+            requests: Default::default(),
+        };
+        let embeddings = create_embeddings_for_sentences(
+            sentences,
+            &provider,
+            IdScheme::Range,
+            None,
+        ).await.unwrap();
+        let requests = provider.requests.borrow();
+        // the batch, each input, and the halved long input
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[1], vec!["Short."]);
+        assert!(requests[3][0].len() <= 20);
+        assert!("This sentence is way too long.".starts_with(&requests[3][0]));
+        assert_eq!(embeddings[0].embedding, vec![6.0]);
+        assert_eq!(
+            embeddings[1].embedding,
+            vec![requests[3][0].len() as f32],
+        );
+        // the content is intact
+        assert_eq!(embeddings[1].content, "This sentence is way too long.");
+
+        // truncates inputs to the limit of the provider first
+        let sentences =
+            split_post_into_sentences(&post, LineBreakMode::Whitespace);
+        let provider = LimitedProvider {
+            max_len: 20,
+            max_tokens: Some(5),
+            requests: Default::default(),
+        };
+        create_embeddings_for_sentences(
+            sentences,
+            &provider,
+            IdScheme::Range,
+            None,
+        ).await.unwrap();
+        assert_eq!(
+            *provider.requests.borrow(),
+            vec![vec!["Short.", "This sentence i"]],
+        );
+    }
+
+    #[test]
+    fn content_ids_survive_edits_above() {
+        let mut post = Post {
+            id: "https://example.com/posts/1".to_string(),
+            type_: "Note".to_string(),
+            content: "Same here. Same here. Last one.".to_string(),
+            ..Post::default()
+        };
+        let ids = |post: &Post| -> Vec<String> {
+            split_post_into_sentences(post, LineBreakMode::Whitespace)
+                .iter()
+                .map(|s| s.id_with(IdScheme::Content))
+                .collect()
+        };
+        let before = ids(&post);
+        assert_eq!(before.len(), 3);
+        assert!(before[0].ends_with("-0"));
+        assert!(before[1].ends_with("-1"));
+        let without_occurrence = |id: &str| id[..id.len() - 2].to_string();
+        assert_eq!(
+            without_occurrence(&before[0]),
+            without_occurrence(&before[1]),
+        );
+
+        post.content = format!("A new opening line. {}", post.content);
+        let after = ids(&post);
+        assert_eq!(after[1..], before[..]);
+    }
+
+    #[test]
+    fn parse_range_id_parses_old_style_ids() {
+        const POST_ID: &str = "https://example.com/posts/1";
+        assert_eq!(
+            parse_range_id(&format!("{}#10-22", POST_ID)),
+            Some((POST_ID, Granularity::Sentence, 10..22)),
+        );
+        assert_eq!(
+            parse_range_id(&format!("{}#block-0-22", POST_ID)),
+            Some((POST_ID, Granularity::Block, 0..22)),
+        );
+        assert_eq!(parse_range_id(&format!("{}#name", POST_ID)), None);
+        assert_eq!(parse_range_id(POST_ID), None);
+    }
+
+    #[test]
+    fn sentence_filter_drops_sentences_in_stop_list() {
+        let filter = SentenceFilter::default()
+            .with_stop_list(["Good morning"]);
+        assert!(!filter.accepts("good morning!"));
+        assert!(filter.accepts("good morning, everyone"));
+    }
+}
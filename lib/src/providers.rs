@@ -0,0 +1,80 @@
+//! Providers of embeddings.
+
+use futures::future::LocalBoxFuture;
+
+use crate::error::Error;
+
+/// Provider of embeddings.
+///
+/// Decouples the pipeline from a specific model or service.
+pub trait EmbeddingProvider {
+    /// Embeds given texts.
+    ///
+    /// The i-th vector is the embedding of the i-th text.
+    fn embed<'a>(
+        &'a self,
+        inputs: &'a [String],
+    ) -> LocalBoxFuture<'a, Result<Vec<Vec<f32>>, Error>>;
+
+    /// Returns the ID of the model that makes embeddings.
+    ///
+    /// Embeddings of different models are not comparable.
+    fn model_id(&self) -> &str;
+
+    /// Returns the number of dimensions of embeddings.
+    fn dimensions(&self) -> usize;
+
+    /// Returns the maximum number of tokens of an input.
+    ///
+    /// Inputs are truncated to this number of tokens estimated by
+    /// [`estimate_tokens`]. `None` if inputs may be of any length; e.g., the
+    /// provider truncates them itself.
+    fn max_input_tokens(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Embeds a single text.
+pub async fn embed_one(
+    provider: &dyn EmbeddingProvider,
+    input: &str,
+) -> Result<Vec<f32>, Error> {
+    let inputs = [input.to_string()];
+    provider.embed(&inputs).await?
+        .pop()
+        .ok_or_else(|| Error::InvalidData("no embedding returned".to_string()))
+}
+
+/// Roughly estimates the number of tokens in a given text.
+///
+/// Counts three ASCII characters as a token and any other character as two
+/// tokens, which should overestimate the number of tokens of most texts.
+pub fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(|ch| ch.is_ascii()).count();
+    let others = text.chars().count() - ascii;
+    tokens_of(ascii, others)
+}
+
+/// Truncates a given text so that its estimated number of tokens does not
+/// exceed `max_tokens`.
+///
+/// See [`estimate_tokens`] for how tokens are estimated.
+pub fn truncate_to_tokens(text: &str, max_tokens: usize) -> &str {
+    let mut ascii = 0usize;
+    let mut others = 0usize;
+    for (i, ch) in text.char_indices() {
+        if ch.is_ascii() {
+            ascii += 1;
+        } else {
+            others += 1;
+        }
+        if tokens_of(ascii, others) > max_tokens {
+            return &text[..i];
+        }
+    }
+    text
+}
+
+fn tokens_of(ascii: usize, others: usize) -> usize {
+    ascii.div_ceil(3) + others * 2
+}
@@ -0,0 +1,475 @@
+//! Fetches posts of a remote ActivityPub actor.
+
+use futures::future;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use reqwest::StatusCode;
+use reqwest::header::{ACCEPT, RETRY_AFTER};
+use std::collections::VecDeque;
+use std::fs::{read_to_string, remove_file, write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::import::convert_html_content;
+use crate::posts::{Post, PostOrigin};
+use crate::sources::{PostSource, post_from_outbox_item};
+
+/// Media type of ActivityPub objects.
+pub const ACTIVITY_JSON: &str = "application/activity+json";
+
+/// Media type of WebFinger responses.
+pub const JRD_JSON: &str = "application/jrd+json";
+
+/// Name of a remote ActivityPub server as a source platform.
+pub const ACTIVITYPUB: &str = "activitypub";
+
+/// Outbox of a remote ActivityPub actor.
+///
+/// Resolves the actor through WebFinger, and follows the pages of the outbox.
+/// Only `Create` activities with embedded objects yield posts.
+///
+/// Requests are at least `request_interval` apart. If a cursor file is
+/// given, the URL of a page is saved in it when all the posts in the
+/// previous pages have been yielded, and fetching resumes from the saved page
+/// next time. The cursor file is removed when the last page is done.
+#[derive(Clone, Debug)]
+pub struct RemoteOutboxSource {
+    account: String,
+    scheme: String,
+    request_interval: Duration,
+    max_retries: usize,
+    cursor_path: Option<PathBuf>,
+}
+
+impl RemoteOutboxSource {
+    /// Creates a source of the posts of a given account.
+    ///
+    /// `account` is either `username@host` (optionally prefixed with '@') or
+    /// the URL of the actor.
+    pub fn new(account: impl Into<String>) -> Self {
+        Self {
+            account: account.into(),
+            scheme: "https".to_string(),
+            request_interval: Duration::from_secs(1),
+            max_retries: 3,
+            cursor_path: None,
+        }
+    }
+
+    /// Sets the URL scheme of WebFinger.
+    ///
+    /// "https" by default. Useful to test against a local server.
+    pub fn with_scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.scheme = scheme.into();
+        self
+    }
+
+    /// Sets the minimum interval between requests.
+    pub fn with_request_interval(mut self, interval: Duration) -> Self {
+        self.request_interval = interval;
+        self
+    }
+
+    /// Sets the maximum number of retries of a rate-limited request.
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the path to the cursor file.
+    pub fn with_cursor_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cursor_path = Some(path.into());
+        self
+    }
+
+    // Returns the saved cursor if any.
+    fn saved_cursor(&self) -> Result<Option<String>, Error> {
+        match self.cursor_path.as_ref() {
+            Some(path) if path.exists() => {
+                let cursor = read_to_string(path)?.trim().to_string();
+                Ok(Some(cursor).filter(|c| !c.is_empty()))
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+impl PostSource for RemoteOutboxSource {
+    fn list_posts_except<'a>(
+        &self,
+        skip: Box<dyn Fn(&PostOrigin) -> bool + 'a>,
+    ) -> LocalBoxStream<'a, Result<Post, Error>> {
+        let fetcher = OutboxFetcher {
+            config: self.clone(),
+            client: reqwest::Client::new(),
+            state: FetchState::NotStarted,
+            items: VecDeque::new(),
+            last_request: None,
+        };
+        stream::unfold(fetcher, |mut fetcher| async move {
+            let post = fetcher.next_post().await?;
+            Some((post, fetcher))
+        })
+            .filter(move |post| future::ready(match post {
+                Ok(post) => !post.origin.as_ref().is_some_and(&skip),
+                Err(_) => true,
+            }))
+            .boxed_local()
+    }
+
+    fn is_complete_listing(&self) -> bool {
+        // a resumed listing misses the pages before the cursor
+        !matches!(self.saved_cursor(), Ok(Some(_)))
+    }
+}
+
+// State of fetching pages.
+enum FetchState {
+    NotStarted,
+    // next page to fetch if any
+    Fetching(Option<String>),
+    Done,
+}
+
+// Fetches the pages of an outbox.
+struct OutboxFetcher {
+    config: RemoteOutboxSource,
+    client: reqwest::Client,
+    state: FetchState,
+    items: VecDeque<serde_json::Value>,
+    last_request: Option<Instant>,
+}
+
+impl OutboxFetcher {
+    // Returns the next post.
+    //
+    // Returns `None` after the last post or an error.
+    async fn next_post(&mut self) -> Option<Result<Post, Error>> {
+        loop {
+            if let Some(item) = self.items.pop_front() {
+                if item.get("type").and_then(|t| t.as_str()) != Some("Create") {
+                    continue;
+                }
+                match post_from_outbox_item(item) {
+                    Some(Ok(mut post)) => {
+                        convert_html_content(&mut post);
+                        post.source_platform = Some(ACTIVITYPUB.to_string());
+                        return Some(Ok(post));
+                    },
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => continue,
+                }
+            }
+            let state = std::mem::replace(&mut self.state, FetchState::Done);
+            let result = match state {
+                FetchState::NotStarted => self.start().await,
+                FetchState::Fetching(Some(page)) => {
+                    self.fetch_page(page).await
+                },
+                FetchState::Fetching(None) => self.finish(),
+                FetchState::Done => return None,
+            };
+            if let Err(e) = result {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    // Starts from the saved cursor or the first page of the outbox.
+    async fn start(&mut self) -> Result<(), Error> {
+        if let Some(cursor) = self.config.saved_cursor()? {
+            println!("resuming outbox from: {}", cursor);
+            self.state = FetchState::Fetching(Some(cursor));
+            return Ok(());
+        }
+        let actor_url = self.resolve_actor().await?;
+        let actor = self.get_json(&actor_url, ACTIVITY_JSON).await?;
+        let outbox_url = actor.get("outbox")
+            .and_then(|outbox| outbox.as_str())
+            .ok_or(Error::InvalidData("actor has no outbox".to_string()))?
+            .to_string();
+        let outbox = self.get_json(&outbox_url, ACTIVITY_JSON).await?;
+        match outbox.get("first") {
+            Some(serde_json::Value::String(first)) => {
+                self.state = FetchState::Fetching(Some(first.clone()));
+            },
+            Some(first) => self.load_page(first.clone())?,
+            None => self.load_page(outbox)?,
+        };
+        Ok(())
+    }
+
+    // Resolves the URL of the actor.
+    async fn resolve_actor(&mut self) -> Result<String, Error> {
+        let account = self.config.account.trim_start_matches('@').to_string();
+        if account.starts_with("http://") || account.starts_with("https://") {
+            return Ok(account);
+        }
+        let (_, host) = account.rsplit_once('@').ok_or(Error::InvalidData(
+            format!("account must be username@host: {}", account),
+        ))?;
+        let mut url = url::Url::parse(&format!(
+            "{}://{}/.well-known/webfinger",
+            self.config.scheme,
+            host,
+        )).map_err(|e| Error::InvalidData(format!("{}", e)))?;
+        url.query_pairs_mut()
+            .append_pair("resource", &format!("acct:{}", account));
+        let jrd = self.get_json(url.as_str(), JRD_JSON).await?;
+        jrd.get("links")
+            .and_then(|links| links.as_array())
+            .and_then(|links| links.iter().find(|link| {
+                link.get("rel").and_then(|r| r.as_str()) == Some("self")
+                    && link.get("type")
+                        .and_then(|t| t.as_str())
+                        .is_some_and(|t| {
+                            t == ACTIVITY_JSON
+                                || t.starts_with("application/ld+json")
+                        })
+            }))
+            .and_then(|link| link.get("href"))
+            .and_then(|href| href.as_str())
+            .map(|href| href.to_string())
+            .ok_or(Error::InvalidData(
+                format!("no actor found through WebFinger: {}", account),
+            ))
+    }
+
+    // Fetches a page.
+    //
+    // Saves the URL of the page as the cursor, because all the items in the
+    // previous pages have been yielded.
+    async fn fetch_page(&mut self, url: String) -> Result<(), Error> {
+        if let Some(path) = self.config.cursor_path.as_ref() {
+            write(path, &url)?;
+        }
+        let page = self.get_json(&url, ACTIVITY_JSON).await?;
+        self.load_page(page)
+    }
+
+    // Queues the items in a page.
+    fn load_page(&mut self, mut page: serde_json::Value) -> Result<(), Error> {
+        let key = if page.get("orderedItems").is_some() {
+            "orderedItems"
+        } else {
+            "items"
+        };
+        let items = page.get_mut(key).map(|items| items.take());
+        if let Some(serde_json::Value::Array(items)) = items {
+            self.items.extend(items);
+        }
+        let next = match page.get("next") {
+            Some(serde_json::Value::String(next)) => Some(next.clone()),
+            Some(next) => next.get("id")
+                .and_then(|id| id.as_str())
+                .map(|id| id.to_string()),
+            None => None,
+        };
+        self.state = FetchState::Fetching(next);
+        Ok(())
+    }
+
+    // Finishes fetching.
+    fn finish(&mut self) -> Result<(), Error> {
+        if let Some(path) = self.config.cursor_path.as_ref() {
+            if path.exists() {
+                remove_file(path)?;
+            }
+        }
+        self.state = FetchState::Done;
+        Ok(())
+    }
+
+    // Gets a JSON resource.
+    //
+    // Waits for the request interval, and retries a rate-limited request.
+    async fn get_json(
+        &mut self,
+        url: &str,
+        accept: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let mut retries = 0;
+        loop {
+            if let Some(last_request) = self.last_request {
+                let elapsed = last_request.elapsed();
+                if elapsed < self.config.request_interval {
+                    tokio::time::sleep(self.config.request_interval - elapsed)
+                        .await;
+                }
+            }
+            println!("fetching: {}", url);
+            self.last_request = Some(Instant::now());
+            let res = self.client
+                .get(url)
+                .header(ACCEPT, accept)
+                .send()
+                .await?;
+            let status = res.status();
+            if status.is_success() {
+                return Ok(res.json().await?);
+            }
+            let is_rate_limited = status == StatusCode::TOO_MANY_REQUESTS
+                || status == StatusCode::SERVICE_UNAVAILABLE;
+            if !is_rate_limited || retries >= self.config.max_retries {
+                return Err(Error::HttpError(status));
+            }
+            retries += 1;
+            let wait = res.headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(self.config.request_interval * (1 << retries));
+            println!("rate limited ({}), retrying in {:?}", status, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::stub_server::{
+        StubServer,
+        http_response,
+        request_header,
+        request_target,
+    };
+
+    // Starts a stub HTTP server that serves given JSON bodies by path and
+    // query.
+    //
+    // Returns the host and the recorded requests.
+    async fn start_stub_server(
+        routes: impl Fn(&str) -> HashMap<String, String>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let server = StubServer::bind().await;
+        let host = server.host().to_string();
+        let routes = routes(&host);
+        let requests = server.serve(move |request| {
+            let target = request_target(request);
+            let path = target.split('?').next().unwrap();
+            match routes.get(target).or(routes.get(path)) {
+                Some(body) => http_response(
+                    "200 OK",
+                    &[("Content-Type", "application/json")],
+                    body,
+                ),
+                None => http_response("404 Not Found", &[], ""),
+            }
+        });
+        (host, requests)
+    }
+
+    fn outbox_routes(host: &str) -> HashMap<String, String> {
+        let base = format!("http://{}", host);
+        let create = |id: u32, content: &str| serde_json::json!({
+            "type": "Create",
+            "actor": format!("{}/users/alice", base),
+            "object": {
+                "id": format!("{}/users/alice/statuses/{}", base, id),
+                "type": "Note",
+                "content": content,
+            },
+        });
+        HashMap::from([
+            (
+                "/.well-known/webfinger".to_string(),
+                serde_json::json!({
+                    "subject": format!("acct:alice@{}", host),
+                    "links": [{
+                        "rel": "self",
+                        "type": ACTIVITY_JSON,
+                        "href": format!("{}/users/alice", base),
+                    }],
+                }).to_string(),
+            ),
+            (
+                "/users/alice".to_string(),
+                serde_json::json!({
+                    "id": format!("{}/users/alice", base),
+                    "type": "Person",
+                    "outbox": format!("{}/users/alice/outbox", base),
+                }).to_string(),
+            ),
+            (
+                "/users/alice/outbox".to_string(),
+                serde_json::json!({
+                    "type": "OrderedCollection",
+                    "first": format!("{}/users/alice/outbox?page=1", base),
+                }).to_string(),
+            ),
+            (
+                "/users/alice/outbox?page=1".to_string(),
+                serde_json::json!({
+                    "type": "OrderedCollectionPage",
+                    "orderedItems": [
+                        create(2, "<p>Second &amp; last</p>"),
+                        {
+                            "type": "Announce",
+                            "actor": format!("{}/users/alice", base),
+                            "object": "https://example.com/notes/1",
+                        },
+                    ],
+                    "next": format!("{}/users/alice/outbox?page=2", base),
+                }).to_string(),
+            ),
+            (
+                "/users/alice/outbox?page=2".to_string(),
+                serde_json::json!({
+                    "type": "OrderedCollectionPage",
+                    "orderedItems": [create(1, "<p>First</p>")],
+                }).to_string(),
+            ),
+        ])
+    }
+
+    #[tokio::test]
+    async fn remote_outbox_source_follows_pages() {
+        let (host, requests) = start_stub_server(outbox_routes).await;
+        let cursor_path = std::env::temp_dir()
+            .join(format!("mumble-embedding-cursor-{}", std::process::id()));
+        let source = RemoteOutboxSource::new(format!("@alice@{}", host))
+            .with_scheme("http")
+            .with_request_interval(Duration::from_millis(1))
+            .with_cursor_path(&cursor_path);
+        let posts: Vec<Post> = source.list_posts()
+            .map(|post| post.unwrap())
+            .collect()
+            .await;
+        let ids: Vec<&str> = posts.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids, vec![
+            format!("http://{}/users/alice/statuses/2", host),
+            format!("http://{}/users/alice/statuses/1", host),
+        ]);
+        assert_eq!(posts[0].text(), "Second & last");
+        assert!(!cursor_path.exists());
+        {
+            let requests = requests.lock().unwrap();
+            assert!(
+                request_target(&requests[0])
+                    .starts_with("/.well-known/webfinger?"),
+            );
+            assert!(requests[1..].iter().all(|request| {
+                request_header(request, "accept") == Some(ACTIVITY_JSON)
+            }));
+        }
+
+        // resumes from the cursor
+        write(
+            &cursor_path,
+            format!("http://{}/users/alice/outbox?page=2", host),
+        ).unwrap();
+        assert!(!source.is_complete_listing());
+        let posts: Vec<Post> = source.list_posts()
+            .map(|post| post.unwrap())
+            .collect()
+            .await;
+        assert_eq!(posts.len(), 1);
+        assert!(posts[0].id.ends_with("/statuses/1"));
+        assert!(!cursor_path.exists());
+    }
+}
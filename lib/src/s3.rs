@@ -11,6 +11,8 @@ use core::pin::Pin;
 use core::task::{Context, Poll};
 use futures::stream::Stream;
 
+use crate::error::Error;
+
 type ListObjectsV2FutureOutput = Result<
     ListObjectsV2Output,
     SdkError<ListObjectsV2Error, HttpResponse>,
@@ -46,6 +48,9 @@ impl ObjectList {
 }
 
 /// Stream of listed objects.
+///
+/// A failed request yields an error and ends the stream, so that the stream
+/// ends without an error only if all the objects have been listed.
 pub struct ObjectListStream {
     config: ObjectList,
     objects: Vec<aws_sdk_s3::types::Object>,
@@ -71,7 +76,7 @@ impl ObjectListStream {
 }
 
 impl Stream for ObjectListStream {
-    type Item = aws_sdk_s3::types::Object;
+    type Item = Result<aws_sdk_s3::types::Object, Error>;
 
     fn poll_next(
         mut self: Pin<&mut Self>,
@@ -82,7 +87,7 @@ impl Stream for ObjectListStream {
             self.next_index += 1;
             let object = &self.objects[next_index];
             if object.key.is_some() {
-                return Poll::Ready(Some(object.clone()));
+                return Poll::Ready(Some(Ok(object.clone())));
             } // btw, when does object become None?
         }
         // polls the pending request
@@ -107,11 +112,47 @@ impl Stream for ObjectListStream {
                     cx.waker().wake_by_ref();
                     Poll::Pending
                 },
-                Poll::Ready(_) => return Poll::Ready(None),
-                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => {
+                    self.pending_request = None;
+                    Poll::Ready(Some(Err(e.into())))
+                },
+                Poll::Pending => Poll::Pending,
             }
         } else {
             Poll::Ready(None)
         }
     }
 }
+
+/// Lists the common prefixes of the objects under a given prefix.
+///
+/// Keys are rolled up at the first `delimiter` after `prefix`, so that common
+/// prefixes are like subdirectories; e.g., "objects/users/alice/" under
+/// "objects/users/" with "/".
+pub async fn list_common_prefixes(
+    s3: &aws_sdk_s3::Client,
+    bucket_name: &str,
+    prefix: &str,
+    delimiter: &str,
+) -> Result<Vec<String>, Error> {
+    let mut prefixes = Vec::new();
+    let mut continuation_token: Option<String> = None;
+    loop {
+        let results = s3.list_objects_v2()
+            .bucket(bucket_name)
+            .prefix(prefix)
+            .delimiter(delimiter)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?;
+        prefixes.extend(results.common_prefixes
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|p| p.prefix));
+        continuation_token = results.next_continuation_token;
+        if continuation_token.is_none() {
+            break;
+        }
+    }
+    Ok(prefixes)
+}
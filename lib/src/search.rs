@@ -0,0 +1,117 @@
+//! Filtering and ranking of search results by time.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+// Seconds in a day.
+const SECONDS_PER_DAY: f64 = 86_400.0;
+
+/// Time bounds and recency decay of a search.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeFilter {
+    /// Earliest published date of results.
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    /// Published date that results must be earlier than.
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    /// Rate of the exponential decay of scores per day of age.
+    ///
+    /// No decay if `None` or zero.
+    #[serde(default)]
+    pub recency_decay: Option<f64>,
+}
+
+impl TimeFilter {
+    /// Returns if the filter neither drops nor reorders results.
+    pub fn is_empty(&self) -> bool {
+        self.since.is_none() && self.until.is_none() && !self.has_decay()
+    }
+
+    /// Returns if scores decay.
+    pub fn has_decay(&self) -> bool {
+        self.recency_decay.is_some_and(|decay| decay != 0.0)
+    }
+
+    /// Returns if a result published at a given timestamp passes the bounds.
+    ///
+    /// A result without a timestamp passes only if no bound is given.
+    pub fn matches(&self, published: Option<u64>) -> bool {
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        let Some(published) = published else {
+            return false;
+        };
+        self.since.is_none_or(|since| published >= to_timestamp(&since))
+            && self.until.is_none_or(|until| published < to_timestamp(&until))
+    }
+
+    /// Returns the score of a result; the higher, the better.
+    ///
+    /// The score is `1 - squared_distance / 4`, which is in [0, 1] for unit
+    /// vectors and increases with the cosine similarity, multiplied by
+    /// `exp(-recency_decay * age)` where `age` is in days at `now`.
+    /// A result without a timestamp decays as if it were published at the
+    /// Unix epoch, so that it never ranks above a dated result because of
+    /// missing data.
+    pub fn score(
+        &self,
+        squared_distance: f32,
+        published: Option<u64>,
+        now: u64,
+    ) -> f64 {
+        let similarity = 1.0 - squared_distance as f64 / 4.0;
+        match self.recency_decay {
+            Some(decay) => {
+                let age = now.saturating_sub(published.unwrap_or(0)) as f64
+                    / SECONDS_PER_DAY;
+                similarity * (-decay * age).exp()
+            },
+            None => similarity,
+        }
+    }
+}
+
+/// Returns the Unix timestamp in seconds of a given date.
+///
+/// Zero for a date before the Unix epoch.
+pub fn to_timestamp(date: &DateTime<Utc>) -> u64 {
+    date.timestamp().max(0) as u64
+}
+
+/// Returns the current Unix timestamp in seconds.
+pub fn now_timestamp() -> u64 {
+    to_timestamp(&Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_filter_bounds_and_decays_results() {
+        let date = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+        let filter = TimeFilter {
+            since: Some(date("2023-09-01T00:00:00Z")),
+            until: Some(date("2023-10-01T00:00:00Z")),
+            recency_decay: Some(0.1),
+        };
+        let sep = to_timestamp(&date("2023-09-20T00:00:00Z"));
+        let oct = to_timestamp(&date("2023-10-01T00:00:00Z"));
+        assert!(filter.matches(Some(sep)));
+        assert!(!filter.matches(Some(oct)));
+        assert!(!filter.matches(None));
+        assert!(TimeFilter::default().matches(None));
+
+        // a newer result wins over a slightly closer but older one
+        let now = oct;
+        let older = filter.score(0.40, Some(sep), now);
+        let newer = filter.score(0.44, Some(oct - 86_400), now);
+        assert!(newer > older);
+        // an undated result does not win over a dated one
+        assert!(filter.score(0.0, None, now) < older);
+        assert_eq!(TimeFilter::default().score(2.0, Some(sep), now), 0.5);
+    }
+}
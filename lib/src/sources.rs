@@ -0,0 +1,413 @@
+//! Sources of posts.
+
+use futures::future;
+use futures::stream::{self, LocalBoxStream, Stream, StreamExt};
+use std::fs::{File, read_dir};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::error::Error;
+use crate::posts::{Post, PostOrigin};
+use crate::s3::{ObjectList, list_common_prefixes};
+
+/// Source of posts; e.g., an S3 bucket or an export.
+pub trait PostSource {
+    /// Lists all posts.
+    ///
+    /// See [`PostSource::list_posts_except`] for errors.
+    fn list_posts<'a>(&self) -> LocalBoxStream<'a, Result<Post, Error>> {
+        self.list_posts_except(Box::new(|_| false))
+    }
+
+    /// Lists posts except for skipped ones.
+    ///
+    /// `skip` is called with the origin of each post before the post is
+    /// loaded, and the post is not loaded if `skip` returns `true`.
+    ///
+    /// A post that is listed but fails to load yields
+    /// [`Error::PostLoadError`]. Any other error means that the listing
+    /// failed, and posts may be missing from it.
+    fn list_posts_except<'a>(
+        &self,
+        skip: Box<dyn Fn(&PostOrigin) -> bool + 'a>,
+    ) -> LocalBoxStream<'a, Result<Post, Error>>;
+
+    /// Returns if the source lists all the posts.
+    ///
+    /// Posts missing from an incomplete listing are not regarded as deleted.
+    fn is_complete_listing(&self) -> bool {
+        true
+    }
+}
+
+/// Prefix of the objects of users in the S3 bucket of Mumble.
+pub const USERS_PREFIX: &str = "objects/users/";
+
+/// Lists the usernames in the S3 bucket of Mumble.
+///
+/// Every common prefix `objects/users/{username}/` is a user.
+/// Loads the AWS configuration from the environment.
+pub async fn list_s3_usernames(
+    bucket_name: &str,
+) -> Result<Vec<String>, Error> {
+    let config = aws_config::load_from_env().await;
+    let client = aws_sdk_s3::Client::new(&config);
+    let prefixes =
+        list_common_prefixes(&client, bucket_name, USERS_PREFIX, "/").await?;
+    Ok(prefixes
+        .iter()
+        .filter_map(|prefix| prefix.strip_prefix(USERS_PREFIX))
+        .filter_map(|username| username.strip_suffix('/'))
+        .filter(|username| !username.is_empty())
+        .map(|username| username.to_string())
+        .collect())
+}
+
+/// Lists all posts of a specified user in the S3 bucket of Mumble.
+///
+/// Shorthand for listing posts from an [`S3PostSource`].
+pub async fn list_posts(
+    bucket_name: &str,
+    username: &str,
+) -> impl Stream<Item = Result<Post, Error>> {
+    S3PostSource::new(bucket_name, username).await.list_posts()
+}
+
+/// Posts of a user in the S3 bucket of Mumble.
+///
+/// Posts are stored in `objects/users/{username}/posts/`.
+pub struct S3PostSource {
+    bucket_name: String,
+    username: String,
+    config: aws_config::SdkConfig,
+}
+
+impl S3PostSource {
+    /// Creates a source of the posts of a given user.
+    ///
+    /// Loads the AWS configuration from the environment.
+    pub async fn new(
+        bucket_name: impl Into<String>,
+        username: impl Into<String>,
+    ) -> Self {
+        Self {
+            bucket_name: bucket_name.into(),
+            username: username.into(),
+            config: aws_config::load_from_env().await,
+        }
+    }
+}
+
+impl PostSource for S3PostSource {
+    fn list_posts_except<'a>(
+        &self,
+        skip: Box<dyn Fn(&PostOrigin) -> bool + 'a>,
+    ) -> LocalBoxStream<'a, Result<Post, Error>> {
+        let client = aws_sdk_s3::Client::new(&self.config);
+        let bucket_name = self.bucket_name.clone();
+        let config = self.config.clone();
+        ObjectList::new(
+            &bucket_name,
+            format!("{}{}/posts/", USERS_PREFIX, self.username),
+            client,
+        )
+            .into_stream()
+            .filter(move |o| future::ready(match o {
+                Ok(o) => !skip(&PostOrigin {
+                    key: o.key.clone().unwrap_or_default(),
+                    e_tag: o.e_tag.clone(),
+                }),
+                Err(_) => true,
+            }))
+            .then(move |o| {
+                let bucket_name = bucket_name.clone();
+                let config = config.clone();
+                async move { load_post(bucket_name, o?, config).await }
+            })
+            .boxed_local()
+    }
+}
+
+async fn load_post(
+    bucket_name: String,
+    object: aws_sdk_s3::types::Object,
+    config: aws_config::SdkConfig,
+) -> Result<Post, Error> {
+    let client = aws_sdk_s3::Client::new(&config);
+    let key = object.key
+        .ok_or(Error::InvalidData("missing key".to_string()))?;
+    println!("retrieving object: {}", key);
+    let load = async {
+        let result = client.get_object()
+            .bucket(bucket_name)
+            .key(key.clone())
+            .send().await?;
+        let e_tag = result.e_tag.or(object.e_tag);
+        let body = result.body.collect().await?;
+        let post = serde_json::from_slice::<Post>(&body.into_bytes())?;
+        Ok::<_, Error>((post, e_tag))
+    };
+    match load.await {
+        Ok((mut post, e_tag)) => {
+            post.origin = Some(PostOrigin { key, e_tag });
+            Ok(post)
+        },
+        Err(e) => Err(Error::PostLoadError(key, Box::new(e))),
+    }
+}
+
+/// Local directory of post JSON files.
+///
+/// Every file with the `.json` extension in the directory is a post.
+/// The ETag of a file is made of its modification time and size.
+pub struct DirectoryPostSource {
+    dir: PathBuf,
+}
+
+impl DirectoryPostSource {
+    /// Creates a source of the posts in a given directory.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    // Lists the origins of the post files in order of name.
+    fn list_origins(&self) -> Result<Vec<PostOrigin>, Error> {
+        let mut origins = Vec::new();
+        for entry in read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !path.is_file() || path.extension() != Some("json".as_ref()) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos());
+            origins.push(PostOrigin {
+                key: path.to_string_lossy().to_string(),
+                e_tag: Some(format!("{:x}-{:x}", modified, metadata.len())),
+            });
+        }
+        origins.sort_by(|lhs, rhs| lhs.key.cmp(&rhs.key));
+        Ok(origins)
+    }
+}
+
+impl PostSource for DirectoryPostSource {
+    fn list_posts_except<'a>(
+        &self,
+        skip: Box<dyn Fn(&PostOrigin) -> bool + 'a>,
+    ) -> LocalBoxStream<'a, Result<Post, Error>> {
+        let origins = match self.list_origins() {
+            Ok(origins) => origins,
+            Err(e) => return stream::once(future::ready(Err(e))).boxed_local(),
+        };
+        stream::iter(origins)
+            .filter(move |origin| future::ready(!skip(origin)))
+            .map(|origin| {
+                println!("loading post file: {}", origin.key);
+                let post: Result<Post, Error> = File::open(&origin.key)
+                    .map_err(Error::from)
+                    .and_then(|file| Ok(serde_json::from_reader(file)?));
+                match post {
+                    Ok(mut post) => {
+                        post.origin = Some(origin);
+                        Ok(post)
+                    },
+                    Err(e) =>
+                        Err(Error::PostLoadError(origin.key, Box::new(e))),
+                }
+            })
+            .boxed_local()
+    }
+}
+
+/// ActivityPub outbox saved in a JSON file; e.g., `outbox.json`.
+///
+/// The outbox is an `OrderedCollection` whose `orderedItems` or first page
+/// has the items. `Create` and `Update` activities yield their objects, and
+/// `Delete` activities yield `Tombstone`s. Items that are objects rather than
+/// activities are yielded as they are. Other items are ignored.
+///
+/// The key of a post is its ID, and it has no ETag.
+pub struct OutboxPostSource {
+    path: PathBuf,
+}
+
+impl OutboxPostSource {
+    /// Creates a source of the posts in a given outbox file.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Loads the items in the outbox.
+    fn load_items(&self) -> Result<Vec<serde_json::Value>, Error> {
+        println!("loading outbox: {:?}", self.path);
+        load_outbox_items(&self.path)
+    }
+}
+
+impl PostSource for OutboxPostSource {
+    fn list_posts_except<'a>(
+        &self,
+        skip: Box<dyn Fn(&PostOrigin) -> bool + 'a>,
+    ) -> LocalBoxStream<'a, Result<Post, Error>> {
+        let posts = self.load_items().map(|items| items
+            .into_iter()
+            .filter_map(post_from_outbox_item)
+            .collect());
+        stream_loaded_posts(posts, skip)
+    }
+}
+
+/// Streams posts already loaded in memory.
+///
+/// Posts are skipped by their origins. Fails if `posts` is an error.
+pub fn stream_loaded_posts<'a>(
+    posts: Result<Vec<Result<Post, Error>>, Error>,
+    skip: Box<dyn Fn(&PostOrigin) -> bool + 'a>,
+) -> LocalBoxStream<'a, Result<Post, Error>> {
+    let posts = match posts {
+        Ok(posts) => posts,
+        Err(e) => return stream::once(future::ready(Err(e))).boxed_local(),
+    };
+    stream::iter(posts)
+        .filter(move |post| future::ready(match post {
+            Ok(post) => !post.origin.as_ref().is_some_and(&skip),
+            Err(_) => true,
+        }))
+        .boxed_local()
+}
+
+/// Loads the items in an outbox file.
+pub fn load_outbox_items(
+    path: impl AsRef<Path>,
+) -> Result<Vec<serde_json::Value>, Error> {
+    parse_outbox_items(File::open(path)?)
+}
+
+/// Parses the items in an outbox.
+pub fn parse_outbox_items(
+    reader: impl Read,
+) -> Result<Vec<serde_json::Value>, Error> {
+    let mut outbox: serde_json::Value = serde_json::from_reader(reader)?;
+    let items = match outbox.get_mut("orderedItems") {
+        Some(items) => items.take(),
+        None => outbox
+            .get_mut("first")
+            .and_then(|first| first.get_mut("orderedItems"))
+            .map(|items| items.take())
+            .ok_or(Error::InvalidData(
+                "outbox must have orderedItems or an embedded first page"
+                    .to_string(),
+            ))?,
+    };
+    match items {
+        serde_json::Value::Array(items) => Ok(items),
+        _ => Err(Error::InvalidData("orderedItems must be an array".into())),
+    }
+}
+
+/// Extracts a post from an item in an outbox.
+///
+/// The origin of the post is its ID.
+/// Returns `None` if the item has no post; e.g., an `Announce` activity or a
+/// `Create` activity whose object is a link.
+pub fn post_from_outbox_item(
+    mut item: serde_json::Value,
+) -> Option<Result<Post, Error>> {
+    let type_ = item.get("type")?.as_str()?.to_string();
+    let object = match type_.as_str() {
+        "Create" | "Update" => item.get_mut("object")?.take(),
+        "Delete" => {
+            let id = match item.get("object")? {
+                serde_json::Value::String(id) => id.clone(),
+                object => object.get("id")?.as_str()?.to_string(),
+            };
+            serde_json::json!({ "id": id, "type": "Tombstone" })
+        },
+        _ if item.get("id").is_some() && item.get("actor").is_none() => item,
+        _ => return None,
+    };
+    if !object.is_object() {
+        return None;
+    }
+    Some(serde_json::from_value::<Post>(object)
+        .map(|mut post| {
+            post.origin = Some(PostOrigin {
+                key: post.id.clone(),
+                e_tag: None,
+            });
+            post
+        })
+        .map_err(Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_from_outbox_item_handles_activities() {
+        let create = serde_json::json!({
+            "type": "Create",
+            "actor": "https://example.com/users/test",
+            "object": {
+                "id": "https://example.com/posts/1",
+                "type": "Note",
+                "content": "Hello.",
+            },
+        });
+        let post = post_from_outbox_item(create).unwrap().unwrap();
+        assert_eq!(post.id, "https://example.com/posts/1");
+        assert_eq!(post.type_, "Note");
+        assert_eq!(post.origin.unwrap().key, "https://example.com/posts/1");
+
+        let delete = serde_json::json!({
+            "type": "Delete",
+            "actor": "https://example.com/users/test",
+            "object": "https://example.com/posts/2",
+        });
+        let post = post_from_outbox_item(delete).unwrap().unwrap();
+        assert_eq!(post.id, "https://example.com/posts/2");
+        assert_eq!(post.type_, "Tombstone");
+
+        let announce = serde_json::json!({
+            "type": "Announce",
+            "actor": "https://example.com/users/test",
+            "object": "https://example.com/posts/3",
+        });
+        assert!(post_from_outbox_item(announce).is_none());
+    }
+
+    #[tokio::test]
+    async fn directory_post_source_tells_load_errors_from_listing_errors() {
+        let dir = std::env::temp_dir()
+            .join(format!("mumble-embedding-sources-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("1.json"),
+            r#"{"id":"https://example.com/posts/1","type":"Note"}"#,
+        ).unwrap();
+        std::fs::write(dir.join("2.json"), "{").unwrap();
+        let posts: Vec<_> = DirectoryPostSource::new(&dir)
+            .list_posts()
+            .collect()
+            .await;
+        assert_eq!(posts.len(), 2);
+        assert_eq!(
+            posts[0].as_ref().unwrap().id,
+            "https://example.com/posts/1",
+        );
+        assert!(posts[1].as_ref().unwrap_err().is_post_load_error());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let posts: Vec<_> = DirectoryPostSource::new(&dir)
+            .list_posts()
+            .collect()
+            .await;
+        assert_eq!(posts.len(), 1);
+        assert!(!posts[0].as_ref().unwrap_err().is_post_load_error());
+    }
+}
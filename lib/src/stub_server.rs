@@ -0,0 +1,131 @@
+//! Stub HTTP server for tests.
+
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Stub HTTP server that answers each request on a new connection.
+pub struct StubServer {
+    listener: TcpListener,
+    host: String,
+}
+
+impl StubServer {
+    /// Binds a stub server to a free local port.
+    pub async fn bind() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        Self { listener, host }
+    }
+
+    /// Returns the host; i.e., the address and port.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Starts serving requests.
+    ///
+    /// `respond` is given a whole request and returns a whole response; see
+    /// [`http_response`].
+    /// Returns the recorded requests.
+    pub fn serve(
+        self,
+        mut respond: impl FnMut(&str) -> String + Send + 'static,
+    ) -> Arc<Mutex<Vec<String>>> {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = self.listener.accept().await.unwrap();
+                let request = read_request(&mut socket).await;
+                let response = respond(&request);
+                recorded.lock().unwrap().push(request);
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        requests
+    }
+}
+
+// Reads the head and body of a request.
+async fn read_request(socket: &mut tokio::net::TcpStream) -> String {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let head_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        let n = socket.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            break buf.len();
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let content_length = String::from_utf8_lossy(&buf[..head_end])
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.eq_ignore_ascii_case("content-length") {
+                value.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(0);
+    while buf.len() < head_end + content_length {
+        let n = socket.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    String::from_utf8_lossy(&buf).to_string()
+}
+
+/// Makes a response that closes the connection.
+///
+/// `status` is a status code and reason; e.g., "200 OK".
+pub fn http_response(
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> String {
+    let headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    format!(
+        concat!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\n",
+            "Connection: close\r\n\r\n{}",
+        ),
+        status,
+        headers,
+        body.len(),
+        body,
+    )
+}
+
+/// Returns the target; i.e., the path and query, of a request.
+pub fn request_target(request: &str) -> &str {
+    request.split(' ').nth(1).unwrap_or("")
+}
+
+/// Returns the value of a header in a request.
+pub fn request_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n\r\n")
+        .next()
+        .unwrap()
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.eq_ignore_ascii_case(name) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+}
@@ -1,42 +1,163 @@
 //! Text processing.
 
 use core::ops::Range;
+use core::str::FromStr;
+use serde::Serialize;
 
+use crate::error::Error;
 use crate::markdown::{Fragment, FragmentContent};
 use crate::markdown::TextBlock;
 
+/// How a line break in a text block is treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineBreakMode {
+    /// Line break is an ordinary whitespace.
+    #[default]
+    Whitespace,
+    /// Line break is a sentence boundary.
+    Boundary,
+    /// Line break is a sentence boundary if a text block consists of multiple
+    /// lines and none of them ends with a terminal punctuation.
+    ///
+    /// Suitable for chat-style posts written one thought per line.
+    Auto,
+}
+
+impl FromStr for LineBreakMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "whitespace" => Ok(Self::Whitespace),
+            "boundary" => Ok(Self::Boundary),
+            "auto" => Ok(Self::Auto),
+            _ => Err(Error::InvalidData(format!(
+                "line break mode must be one of whitespace, boundary, or auto \
+                 but got {}",
+                s,
+            ))),
+        }
+    }
+}
+
 /// Extracts sentences from a given [`TextBlock`].
 ///
-/// A code block is treated as a single sentence.
+/// A code block is treated as a single sentence. The range of a sentence is
+/// in bytes of the source of the text block.
 pub fn extract_sentences(
     text_block: &TextBlock,
+    line_break_mode: LineBreakMode,
 ) -> Vec<(String, Range<usize>)> {
     match text_block {
         TextBlock::Text(fragments) =>
-            extract_sentences_from_fragments(fragments),
+            extract_sentences_from_fragments(fragments, line_break_mode),
         TextBlock::Code { code, range, .. } =>
             vec![(code.clone(), range.clone())],
     }
 }
 
+/// Sentences extracted from a [`TextBlock`] along with the transitions of the
+/// transducer that segmented it.
+///
+/// Intended for debugging segmentation.
+#[derive(Clone, Debug, Serialize)]
+pub struct SegmentationTrace {
+    /// Whether line breaks were treated as sentence boundaries.
+    pub line_break_as_boundary: bool,
+    /// Transitions of the transducer.
+    ///
+    /// Empty for a code block.
+    pub transitions: Vec<Transition>,
+    /// Extracted sentences.
+    pub sentences: Vec<(String, Range<usize>)>,
+}
+
+/// Transition of the transducer that segments text into sentences.
+#[derive(Clone, Debug, Serialize)]
+pub struct Transition {
+    /// Input that caused the transition.
+    pub input: TransitionInput,
+    /// Byte offset of the input in the source.
+    pub index: usize,
+    /// State before the transition.
+    pub from: String,
+    /// State after the transition.
+    pub to: String,
+    /// Tokens output by the transition.
+    pub output: Vec<Token>,
+}
+
+/// Input to the transducer.
+#[derive(Clone, Debug, Serialize)]
+pub enum TransitionInput {
+    /// Character in ordinary text.
+    Character(char),
+    /// Line break treated as a sentence boundary.
+    LineBreak,
+    /// Token string that is never split; e.g., inline code and URL.
+    String(String),
+    /// End of the text block.
+    End,
+}
+
+/// Extracts sentences from a given [`TextBlock`] and records the transitions
+/// of the transducer.
+pub fn trace_sentences(
+    text_block: &TextBlock,
+    line_break_mode: LineBreakMode,
+) -> SegmentationTrace {
+    match text_block {
+        TextBlock::Text(fragments) => {
+            let mut transducer = Transducer::new(
+                is_line_break_boundary(fragments, line_break_mode),
+            );
+            transducer.transitions = Some(Vec::new());
+            let sentences = run_transducer(&mut transducer, fragments);
+            SegmentationTrace {
+                line_break_as_boundary: transducer.line_break_as_boundary,
+                transitions: transducer.transitions.unwrap_or_default(),
+                sentences,
+            }
+        },
+        TextBlock::Code { code, range, .. } => SegmentationTrace {
+            line_break_as_boundary: false,
+            transitions: Vec::new(),
+            sentences: vec![(code.clone(), range.clone())],
+        },
+    }
+}
+
 fn extract_sentences_from_fragments(
     fragments: &Vec<Fragment>,
+    line_break_mode: LineBreakMode,
 ) -> Vec<(String, Range<usize>)> {
-    let (mut tokens, state): (Vec<Token>, TransducerState) = fragments
-        .iter()
-        .fold(
-            (Vec::with_capacity(10), TransducerState::Initial),
-            |(mut tokens, state), fragment| {
-                let (new_tokens, state) =
-                    segment_fragment(state, fragment);
-                tokens.extend(new_tokens);
-                (tokens, state)
-            },
-        );
-    let mut transducer = Transducer::new_from(
-        state,
-        fragments.last().map(|(_, r)| r.end).unwrap_or(0),
+    let mut transducer = Transducer::new(
+        is_line_break_boundary(fragments, line_break_mode),
     );
+    run_transducer(&mut transducer, fragments)
+}
+
+fn is_line_break_boundary(
+    fragments: &[Fragment],
+    line_break_mode: LineBreakMode,
+) -> bool {
+    match line_break_mode {
+        LineBreakMode::Whitespace => false,
+        LineBreakMode::Boundary => true,
+        LineBreakMode::Auto => is_line_oriented(fragments),
+    }
+}
+
+fn run_transducer(
+    transducer: &mut Transducer,
+    fragments: &[Fragment],
+) -> Vec<(String, Range<usize>)> {
+    let mut tokens: Vec<Token> = Vec::with_capacity(10);
+    for fragment in fragments {
+        tokens.extend(segment_fragment(transducer, fragment));
+    }
+    transducer.byte_index =
+        fragments.last().map(|(_, r)| r.end).unwrap_or(0);
     tokens.extend(transducer.finish());
     let senetences: Vec<(String, Range<usize>)> = tokens
         .into_iter()
@@ -70,14 +191,37 @@ fn extract_sentences_from_fragments(
         .collect()
 }
 
+// Returns if given fragments consist of multiple lines none of which ends
+// with a terminal punctuation.
+fn is_line_oriented(fragments: &[Fragment]) -> bool {
+    let text: String = fragments
+        .iter()
+        .map(|(content, _)| content.text().as_str())
+        .collect();
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines.len() >= MIN_LINES_FOR_AUTO_BOUNDARY
+        && lines.iter().all(|line| {
+            !line.ends_with(|ch: char| ch == '.' || ch.is_sentence_break())
+        })
+}
+
+// Minimum number of lines in a text block to make line breaks sentence
+// boundaries in `LineBreakMode::Auto`.
+const MIN_LINES_FOR_AUTO_BOUNDARY: usize = 2;
+
 fn segment_fragment(
-    state: TransducerState,
+    transducer: &mut Transducer,
     (content, range): &Fragment,
-) -> (Vec<Token>, TransducerState) {
+) -> Vec<Token> {
     match content {
-        FragmentContent::Text(text) => segment_text(state, text, range),
-        FragmentContent::Code(code) => pass_token_string(state, code, range),
-        FragmentContent::Url(url) => pass_token_string(state, url, range),
+        FragmentContent::Text(text) => segment_text(transducer, text, range),
+        FragmentContent::Code(code) =>
+            pass_token_string(transducer, code, range),
+        FragmentContent::Url(url) => pass_token_string(transducer, url, range),
     }
 }
 
@@ -85,18 +229,19 @@ fn segment_fragment(
 //
 // A sentence breaks at a period, question mark, exclamation mark,
 // semicolon, or 句点('。').
+// A line break also breaks a sentence if the transducer is configured so.
 fn segment_text(
-    state: TransducerState,
+    transducer: &mut Transducer,
     text: &String,
     range: &Range<usize>,
-) -> (Vec<Token>, TransducerState) {
+) -> Vec<Token> {
     // labels each character
-    let mut transducer = Transducer::new_from(state, range.start);
+    transducer.byte_index = range.start;
     let mut tokens: Vec<Token> = Vec::with_capacity(text.len());
     for ch in text.chars() {
         tokens.extend(transducer.next(ch));
     }
-    (tokens, transducer.state.unwrap())
+    tokens
 }
 
 // Passes a given token string through a transducer.
@@ -104,32 +249,39 @@ fn segment_text(
 // A token string is not split into sentences, but the transducer state may
 // transition.
 fn pass_token_string(
-    state: TransducerState,
+    transducer: &mut Transducer,
     text: &String,
     range: &Range<usize>,
-) -> (Vec<Token>, TransducerState) {
-    let mut transducer = Transducer::new_from(state, range.start);
-    let tokens = transducer.next_string(text);
-    (tokens, transducer.state.unwrap())
+) -> Vec<Token> {
+    transducer.byte_index = range.start;
+    transducer.next_string(text)
 }
 
 struct Transducer {
     // `state` internally becomes `None` while it is transitioning.
     state: Option<TransducerState>,
-    char_index: usize,
+    byte_index: usize,
+    // whether a line break ('\n') is a sentence boundary.
+    line_break_as_boundary: bool,
+    // transitions are recorded only if this is `Some`.
+    transitions: Option<Vec<Transition>>,
 }
 
-#[derive(Clone, Debug)]
-enum TokenType {
-    // Character.
+/// Type of a token output by the transducer.
+#[derive(Clone, Debug, Serialize)]
+pub enum TokenType {
+    /// Character.
     Character(char),
-    // String.
+    /// String.
     String(String),
-    // Sentence break.
+    /// Sentence break.
     SentenceBreak,
 }
 
-type Token = (TokenType, Range<usize>);
+/// Token output by the transducer.
+///
+/// Second element is the byte range in the input.
+pub type Token = (TokenType, Range<usize>);
 
 #[derive(Clone, Debug)]
 enum TransducerState {
@@ -150,33 +302,83 @@ enum TransducerState {
 }
 
 impl Transducer {
-    fn new_from(state: TransducerState, start: usize) -> Self {
+    fn new(line_break_as_boundary: bool) -> Self {
         Self {
-            state: Some(state),
-            char_index: start,
+            state: Some(TransducerState::Initial),
+            byte_index: 0,
+            line_break_as_boundary,
+            transitions: None,
         }
     }
 
     fn next(&mut self, ch: char) -> Vec<Token> {
-        let (next_state, output) = self.state.take().unwrap().next(self, ch);
-        self.char_index += 1;
+        let state = self.state.take().unwrap();
+        let from = self.describe_state(&state);
+        let (input, (next_state, output)) =
+            if ch == '\n' && self.line_break_as_boundary {
+                (TransitionInput::LineBreak, state.line_break(self))
+            } else {
+                (TransitionInput::Character(ch), state.next(self, ch))
+            };
+        self.record_transition(input, from, &next_state, &output);
+        self.byte_index += ch.len_utf8();
         self.state.replace(next_state);
         output
     }
 
     fn next_string(&mut self, text: &String) -> Vec<Token> {
-        let (next_state, output) =
-            self.state.take().unwrap().next_string(self, text);
-        self.char_index += text.len();
+        let state = self.state.take().unwrap();
+        let from = self.describe_state(&state);
+        let (next_state, output) = state.next_string(self, text);
+        self.record_transition(
+            TransitionInput::String(text.clone()),
+            from,
+            &next_state,
+            &output,
+        );
+        self.byte_index += text.len();
         self.state.replace(next_state);
         output
     }
 
     fn finish(&mut self) -> Vec<Token> {
-        let (next_state, output) = self.state.take().unwrap().finish(self);
+        let state = self.state.take().unwrap();
+        let from = self.describe_state(&state);
+        let (next_state, output) = state.finish(self);
+        self.record_transition(
+            TransitionInput::End,
+            from,
+            &next_state,
+            &output,
+        );
         self.state.replace(next_state);
         output
     }
+
+    // Describes a given state if transitions are recorded.
+    fn describe_state(&self, state: &TransducerState) -> Option<String> {
+        self.transitions.as_ref().map(|_| format!("{:?}", state))
+    }
+
+    fn record_transition(
+        &mut self,
+        input: TransitionInput,
+        from: Option<String>,
+        to: &TransducerState,
+        output: &[Token],
+    ) {
+        if let (Some(transitions), Some(from)) =
+            (self.transitions.as_mut(), from)
+        {
+            transitions.push(Transition {
+                input,
+                index: self.byte_index,
+                from,
+                to: format!("{:?}", to),
+                output: output.to_vec(),
+            });
+        }
+    }
 }
 
 impl TransducerState {
@@ -232,6 +434,18 @@ impl TransducerState {
         }
     }
 
+    fn line_break(self, transducer: &Transducer) -> (Self, Vec<Token>) {
+        match self {
+            Self::Initial => (Self::Initial, Vec::new()),
+            Self::Character => Self::character_line_break(transducer),
+            Self::Whitespace(start) => Self::whitespace_line_break(start),
+            // same as the end of the text
+            Self::PeriodAnd(start) => Self::period_and_finish(start),
+            Self::WhitespacePeriodAnd(_, p_start) =>
+                Self::whitespace_period_and_finish(p_start),
+        }
+    }
+
     fn initial_next(
         transducer: &Transducer,
         ch: char,
@@ -247,8 +461,8 @@ impl TransducerState {
                     vec![(
                         TokenType::Character(ch),
                         Range {
-                            start: transducer.char_index,
-                            end: transducer.char_index + 1,
+                            start: transducer.byte_index,
+                            end: transducer.byte_index + ch.len_utf8(),
                         }
                     )],
                 )
@@ -265,8 +479,8 @@ impl TransducerState {
             vec![(
                 TokenType::String(text.clone()),
                 Range {
-                    start: transducer.char_index,
-                    end: transducer.char_index + text.chars().count(),
+                    start: transducer.byte_index,
+                    end: transducer.byte_index + text.len(),
                 },
             )],
         )
@@ -283,12 +497,12 @@ impl TransducerState {
         match ch {
             ch if ch.is_ascii_whitespace() => {
                 // deters the output and squashes consecutive whitespaces
-                (Self::Whitespace(transducer.char_index), Vec::new())
+                (Self::Whitespace(transducer.byte_index), Vec::new())
             },
             '.' => {
                 // deters the output
                 // and determines if this is the end of the sentence
-                (Self::PeriodAnd(transducer.char_index), Vec::new())
+                (Self::PeriodAnd(transducer.byte_index), Vec::new())
             },
             ch if ch.is_sentence_break() => {
                 // determines this is the end of the sentence
@@ -298,15 +512,15 @@ impl TransducerState {
                         (
                             TokenType::Character(ch),
                             Range {
-                                start: transducer.char_index,
-                                end: transducer.char_index + 1,
+                                start: transducer.byte_index,
+                                end: transducer.byte_index + ch.len_utf8(),
                             },
                         ),
                         (
                             TokenType::SentenceBreak,
                             Range {
-                                start: transducer.char_index + 1,
-                                end: transducer.char_index + 1,
+                                start: transducer.byte_index + ch.len_utf8(),
+                                end: transducer.byte_index + ch.len_utf8(),
                             },
                         ),
                     ],
@@ -318,8 +532,8 @@ impl TransducerState {
                     vec![(
                         TokenType::Character(ch),
                         Range {
-                            start: transducer.char_index,
-                            end: transducer.char_index + 1,
+                            start: transducer.byte_index,
+                            end: transducer.byte_index + ch.len_utf8(),
                         },
                     )],
                 )
@@ -336,8 +550,8 @@ impl TransducerState {
             vec![(
                 TokenType::String(text.clone()),
                 Range {
-                    start: transducer.char_index,
-                    end: transducer.char_index + text.chars().count(),
+                    start: transducer.byte_index,
+                    end: transducer.byte_index + text.len(),
                 },
             )],
         )
@@ -347,6 +561,20 @@ impl TransducerState {
         (Self::Initial, Vec::new())
     }
 
+    fn character_line_break(transducer: &Transducer) -> (Self, Vec<Token>) {
+        // determines the end of the sentence
+        (
+            Self::Initial,
+            vec![(
+                TokenType::SentenceBreak,
+                Range {
+                    start: transducer.byte_index,
+                    end: transducer.byte_index,
+                },
+            )],
+        )
+    }
+
     fn whitespace_next(
         transducer: &Transducer,
         start: usize,
@@ -361,12 +589,33 @@ impl TransducerState {
                 // deters the output
                 // and determines if this is the end of the sentence
                 (
-                    Self::WhitespacePeriodAnd(start, transducer.char_index),
+                    Self::WhitespacePeriodAnd(start, transducer.byte_index),
                     Vec::new(),
                 )
             },
-            ch if ch.is_sentence_break() =>
-                todo!("{}", ch),
+            ch if ch.is_sentence_break() => {
+                // determines the end of the sentence
+                // and drops the preceding whitespace
+                (
+                    Self::Initial,
+                    vec![
+                        (
+                            TokenType::Character(ch),
+                            Range {
+                                start: transducer.byte_index,
+                                end: transducer.byte_index + ch.len_utf8(),
+                            },
+                        ),
+                        (
+                            TokenType::SentenceBreak,
+                            Range {
+                                start: transducer.byte_index + ch.len_utf8(),
+                                end: transducer.byte_index + ch.len_utf8(),
+                            },
+                        ),
+                    ],
+                )
+            },
             _ => {
                 (
                     Self::Character,
@@ -375,14 +624,14 @@ impl TransducerState {
                             TokenType::Character(' '),
                             Range {
                                 start,
-                                end: transducer.char_index,
+                                end: transducer.byte_index,
                             },
                         ),
                         (
                             TokenType::Character(ch),
                             Range {
-                                start: transducer.char_index,
-                                end: transducer.char_index + 1,
+                                start: transducer.byte_index,
+                                end: transducer.byte_index + ch.len_utf8(),
                             },
                         ),
                     ],
@@ -403,14 +652,14 @@ impl TransducerState {
                     TokenType::Character(' '),
                     Range {
                         start,
-                        end: transducer.char_index,
+                        end: transducer.byte_index,
                     },
                 ),
                 (
                     TokenType::String(text.clone()),
                     Range {
-                        start: transducer.char_index,
-                        end: transducer.char_index + text.chars().count(),
+                        start: transducer.byte_index,
+                        end: transducer.byte_index + text.len(),
                     },
                 ),
             ],
@@ -427,7 +676,22 @@ impl TransducerState {
                 TokenType::Character(' '),
                 Range {
                     start,
-                    end: transducer.char_index,
+                    end: transducer.byte_index,
+                },
+            )],
+        )
+    }
+
+    fn whitespace_line_break(start: usize) -> (Self, Vec<Token>) {
+        // determines the end of the sentence
+        // and drops the preceding whitespace
+        (
+            Self::Initial,
+            vec![(
+                TokenType::SentenceBreak,
+                Range {
+                    start,
+                    end: start,
                 },
             )],
         )
@@ -477,8 +741,8 @@ impl TransducerState {
                         (
                             TokenType::Character(ch),
                             Range {
-                                start: transducer.char_index,
-                                end: transducer.char_index + 1,
+                                start: transducer.byte_index,
+                                end: transducer.byte_index + ch.len_utf8(),
                             },
                         ),
                     ],
@@ -506,8 +770,8 @@ impl TransducerState {
                 (
                     TokenType::String(text.clone()),
                     Range {
-                        start: transducer.char_index,
-                        end: transducer.char_index + text.chars().count(),
+                        start: transducer.byte_index,
+                        end: transducer.byte_index + text.len(),
                     },
                 ),
             ],
@@ -590,8 +854,8 @@ impl TransducerState {
                         (
                             TokenType::Character(ch),
                             Range {
-                                start: transducer.char_index,
-                                end: transducer.char_index + 1,
+                                start: transducer.byte_index,
+                                end: transducer.byte_index + ch.len_utf8(),
                             },
                         ),
                     ],
@@ -628,8 +892,8 @@ impl TransducerState {
                 (
                     TokenType::String(text.clone()),
                     Range {
-                        start: transducer.char_index,
-                        end: transducer.char_index + text.chars().count(),
+                        start: transducer.byte_index,
+                        end: transducer.byte_index + text.len(),
                     },
                 ),
             ],
@@ -673,3 +937,85 @@ impl CharExt for char {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::markdown::extract_text_blocks;
+
+    fn sentences_of(input: &str, mode: LineBreakMode) -> Vec<String> {
+        extract_text_blocks(input)
+            .unwrap()
+            .iter()
+            .flat_map(|block| extract_sentences(block, mode))
+            .map(|(sentence, _)| sentence)
+            .collect()
+    }
+
+    #[test]
+    fn extract_sentences_treats_line_breaks_as_whitespaces() {
+        let input = "first line\nsecond line";
+        assert_eq!(
+            sentences_of(input, LineBreakMode::Whitespace),
+            vec!["first line second line"],
+        );
+    }
+
+    #[test]
+    fn extract_sentences_treats_line_breaks_as_boundaries() {
+        let input = "first line\nsecond line. third one\n今日は晴れ";
+        assert_eq!(
+            sentences_of(input, LineBreakMode::Boundary),
+            vec!["first line", "second line.", "third one", "今日は晴れ"],
+        );
+    }
+
+    #[test]
+    fn extract_sentences_detects_line_oriented_text_blocks() {
+        let input = "今日は晴れ\n明日は雨\n\nIt is sunny.\nIt may rain.";
+        assert_eq!(
+            sentences_of(input, LineBreakMode::Auto),
+            vec!["今日は晴れ", "明日は雨", "It is sunny.", "It may rain."],
+        );
+        let input = "It is sunny and\nit may rain tomorrow";
+        assert_eq!(
+            sentences_of(input, LineBreakMode::Auto),
+            vec!["It is sunny and", "it may rain tomorrow"],
+        );
+        let input = "It is sunny and\nit may rain tomorrow.";
+        assert_eq!(
+            sentences_of(input, LineBreakMode::Auto),
+            vec!["It is sunny and it may rain tomorrow."],
+        );
+    }
+
+    #[test]
+    fn trace_sentences_reports_byte_ranges() {
+        let input = "今日は晴れ。明日は雨。";
+        let blocks = extract_text_blocks(input).unwrap();
+        let trace = trace_sentences(&blocks[0], LineBreakMode::Whitespace);
+        assert_eq!(
+            trace.sentences,
+            vec![
+                ("今日は晴れ。".to_string(), 0..18),
+                ("明日は雨。".to_string(), 18..33),
+            ],
+        );
+        assert_eq!(&input[trace.sentences[1].1.clone()], "明日は雨。");
+        let indices: Vec<usize> = trace.transitions
+            .iter()
+            .map(|t| t.index)
+            .take(3)
+            .collect();
+        assert_eq!(indices, vec![0, 3, 6]);
+    }
+
+    #[test]
+    fn extract_sentences_ends_sentences_at_spaced_punctuations() {
+        assert_eq!(
+            sentences_of("wait ! what ?", LineBreakMode::Whitespace),
+            vec!["wait!", "what?"],
+        );
+    }
+}
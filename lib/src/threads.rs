@@ -0,0 +1,168 @@
+//! Threads of posts linked by `inReplyTo`.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::markdown::extract_text_blocks;
+use crate::posts::{Post, PostSentence};
+use crate::text::{LineBreakMode, extract_sentences};
+
+/// Index of posts to resolve the parents of replies.
+///
+/// Only posts of the same source are in the index, so the parent of a reply
+/// to a post of another user is never resolved.
+#[derive(Clone, Debug, Default)]
+pub struct ThreadIndex {
+    posts: HashMap<String, ThreadEntry>,
+}
+
+/// Entry of a post in a [`ThreadIndex`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ThreadEntry {
+    /// ID of the object that the post replies to.
+    pub in_reply_to: Option<String>,
+    /// Snippet of the post given to replies as context; see [`snippet_of`].
+    pub snippet: Option<String>,
+}
+
+impl ThreadIndex {
+    /// Creates an empty index.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an entry of a post.
+    pub fn insert(&mut self, post_id: impl Into<String>, entry: ThreadEntry) {
+        self.posts.insert(post_id.into(), entry);
+    }
+
+    /// Adds a post.
+    ///
+    /// Takes the snippet of the post with a given line break mode.
+    pub fn insert_post(&mut self, post: &Post, line_break_mode: LineBreakMode) {
+        self.insert(post.id.clone(), ThreadEntry {
+            in_reply_to: post.in_reply_to.clone(),
+            snippet: snippet_of(post, line_break_mode),
+        });
+    }
+
+    /// Removes a post.
+    pub fn remove(&mut self, post_id: &str) {
+        self.posts.remove(post_id);
+    }
+
+    /// Returns the entry of a given post.
+    pub fn get(&self, post_id: &str) -> Option<&ThreadEntry> {
+        self.posts.get(post_id)
+    }
+
+    /// Returns the snippet of the parent of a given post.
+    ///
+    /// `None` if the post is not a reply or the parent is not in the index.
+    pub fn parent_snippet(&self, post: &Post) -> Option<&str> {
+        let parent = self.posts.get(post.in_reply_to.as_ref()?)?;
+        parent.snippet.as_deref()
+    }
+
+    /// Returns the ID of the root of the thread where a given post is.
+    ///
+    /// Follows `inReplyTo` as far as the index resolves. The root of a post
+    /// that is not a reply is the post itself, and the root of a reply whose
+    /// parent is not in the index is the parent.
+    pub fn thread_root(&self, post: &Post) -> String {
+        let mut root = post.id.as_str();
+        let mut parent = post.in_reply_to.as_deref();
+        // guards against a cycle, which should not but might exist
+        let mut visited: HashSet<&str> = HashSet::from([root]);
+        while let Some(id) = parent {
+            if !visited.insert(id) {
+                break;
+            }
+            root = id;
+            parent = self.posts
+                .get(id)
+                .and_then(|entry| entry.in_reply_to.as_deref());
+        }
+        root.to_string()
+    }
+}
+
+/// Returns the snippet of a post given to replies as context.
+///
+/// The summary if the post has one; otherwise, the first sentence of the
+/// content. `None` if the post has neither.
+pub fn snippet_of(
+    post: &Post,
+    line_break_mode: LineBreakMode,
+) -> Option<String> {
+    if let Some(summary) = post.summary.as_ref() {
+        if !summary.trim().is_empty() {
+            return Some(summary.trim().to_string());
+        }
+    }
+    extract_text_blocks(post.text())
+        .ok()?
+        .iter()
+        .flat_map(|block| extract_sentences(block, line_break_mode))
+        .map(|(sentence, _)| sentence)
+        .find(|sentence| !sentence.trim().is_empty())
+}
+
+/// Attaches the thread of a post to its units.
+///
+/// Sets the root of the thread to the metadata of every unit, and adds the
+/// snippet of the parent to the context of every unit if `parent_snippet` is
+/// given.
+pub fn attach_thread(
+    mut sentences: Vec<PostSentence>,
+    thread_root: &str,
+    parent_snippet: Option<&str>,
+) -> Vec<PostSentence> {
+    for sentence in sentences.iter_mut() {
+        sentence.metadata.thread_root = Some(thread_root.to_string());
+        if let Some(snippet) = parent_snippet {
+            let context = sentence.context.get_or_insert_with(Default::default);
+            context.parent = Some(snippet.to_string());
+        }
+    }
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(id: &str, in_reply_to: Option<&str>, content: &str) -> Post {
+        Post {
+            id: id.to_string(),
+            type_: "Note".to_string(),
+            content: content.to_string(),
+            in_reply_to: in_reply_to.map(|id| id.to_string()),
+            ..Post::default()
+        }
+    }
+
+    #[test]
+    fn thread_index_resolves_parents_and_roots() {
+        let root = post("1", Some("0"), "The cache broke. I restarted it.");
+        let reply = post("2", Some("1"), "Same here, it was the cache.");
+        let nested = post("3", Some("2"), "Fixed now.");
+        let mut threads = ThreadIndex::new();
+        for post in [&root, &reply] {
+            threads.insert_post(post, LineBreakMode::Whitespace);
+        }
+        assert_eq!(threads.parent_snippet(&reply), Some("The cache broke."));
+        assert_eq!(
+            threads.parent_snippet(&nested),
+            Some("Same here, it was the cache."),
+        );
+        assert_eq!(threads.parent_snippet(&root), None);
+        // "0" is not in the index
+        assert_eq!(threads.thread_root(&nested), "0");
+        assert_eq!(threads.thread_root(&post("4", None, "Hi.")), "4");
+
+        let mut summarized = reply.clone();
+        summarized.summary = Some("cache".to_string());
+        threads.insert_post(&summarized, LineBreakMode::Whitespace);
+        assert_eq!(threads.parent_snippet(&nested), Some("cache"));
+    }
+}